@@ -3,20 +3,37 @@ async function jsonForms(e) {
   const action = e.target.action;
   const method = e.target.dataset.method.toUpperCase();
   const payload = {};
-  const fields = e.target.querySelectorAll("input");
+  const fields = e.target.querySelectorAll("input, select");
   for (const field of fields) {
-    if (field.type == "number") {
+    // Set scores are grouped into an array of {player_one, player_two}
+    if (field.dataset.set !== undefined) {
+      if (field.value !== "") {
+        payload.sets = payload.sets || [];
+        const index = parseInt(field.dataset.set);
+        payload.sets[index] = payload.sets[index] || {};
+        payload.sets[index][field.name] = parseInt(field.value);
+      }
+    } else if (field.tagName == "SELECT" && field.value === "") {
+      // Unselected options are left out of the payload
+      continue;
+    } else if (field.type == "checkbox") {
+      payload[field.name] = field.checked;
+    } else if (field.type == "number" || field.dataset.type == "number") {
       payload[field.name] = parseInt(field.value);
     } else {
       payload[field.name] = field.value;
     }
   }
+  if (payload.sets) {
+    payload.sets = payload.sets.filter(Boolean);
+  }
   const options = {};
   options.redirect;
   options.body = JSON.stringify(payload);
   options.method = method;
   options.headers = {
     "Content-Type": "application/json",
+    "X-CSRF-Token": getCookie("csrf-token"),
   };
   try {
     const response = await fetch(action, options);
@@ -26,12 +43,43 @@ async function jsonForms(e) {
   }
 }
 
+function getCookie(name) {
+  const cookie = document.cookie
+    .split("; ")
+    .find((cookie) => cookie.startsWith(name + "="));
+  return cookie ? decodeURIComponent(cookie.split("=")[1]) : "";
+}
+
+// Errors come back as {code, message, fields, correlation_id}
+async function errorMessage(response) {
+  let json;
+  try {
+    json = await response.json();
+  } catch (e) {
+    return `Request failed with status ${response.status}`;
+  }
+  if (json.fields && json.fields.length) {
+    return json.fields
+      .map((error) => {
+        const set = error.field.match(/^sets\[(\d+)\]$/);
+        return (set ? `Set ${parseInt(set[1]) + 1}: ` : "") + error.message;
+      })
+      .join("\n");
+  }
+  if (json.correlation_id) {
+    return `${json.message} (${json.correlation_id})`;
+  }
+  return json.message;
+}
+
 async function handleResponse(response) {
   if (response.headers.get("Location")) {
     location.assign(response.headers.get("Location"));
   } else if (response.status == 205) {
     window.location.reload();
-  } else if (response.ok) {
+  } else if (!response.ok) {
+    alert(await errorMessage(response));
+  } else {
     const json = await response.json();
     // switch (json.type) {
     //   case
@@ -49,14 +49,32 @@ function populateResponse(league) {
   for (let fixture of league.completed_fixtures) {
     let div = document.createElement("div");
     div.classList.add("completed-fixture");
+    let header = "";
+    let playerOneSets = "";
+    let playerTwoSets = "";
+    for (let i = 0; i < league.max_sets; i++) {
+      let set = fixture.sets[i];
+      header += `<th>${i + 1}</th>`;
+      playerOneSets += `<td>${set ? set.player_one : " - "}</td>`;
+      playerTwoSets += `<td>${set ? set.player_two : " - "}</td>`;
+    }
+    const outcomeLabels = {
+      retired: "ret.",
+      walkover: "W/O",
+      double_walkover: "Double W/O",
+      cancelled: "Cancelled",
+    };
+    const outcomeLabel = outcomeLabels[fixture.outcome];
+    if (outcomeLabel) {
+      header += `<th>${outcomeLabel}</th>`;
+    }
+    if (fixture.result_status == "pending" || fixture.result_status == "disputed") {
+      header += `<th>Unconfirmed</th>`;
+    }
     div.innerHTML = `<table>
-          <tr class='result-header'><th></th><th>1</th><th>2</th><th>3</th></tr>
-          <tr><td ${fixture.player_one_id == fixture.winner ? "style='font-weight:bold'" : ""}>${fixture.player_one_name}</td><td>${fixture.player_one_set_one_games}</td><td>${fixture.player_one_set_two_games}</td>
-          <td>${fixture.player_one_tiebreak_points ? fixture.player_one_tiebreak_points : " - "}</td>
-          </tr>
-          <tr><td ${fixture.player_two_id == fixture.winner ? "style='font-weight:bold'" : ""}>${fixture.player_two_name}</td><td>${fixture.player_two_set_one_games}</td><td>${fixture.player_two_set_two_games}</td>
-          <td>${fixture.player_two_tiebreak_points ? fixture.player_two_tiebreak_points : " - "}</td>
-          </tr>
+          <tr class='result-header'><th></th>${header}</tr>
+          <tr><td ${fixture.player_one_id == fixture.winner ? "style='font-weight:bold'" : ""}>${fixture.player_one_name}</td>${playerOneSets}</tr>
+          <tr><td ${fixture.player_two_id == fixture.winner ? "style='font-weight:bold'" : ""}>${fixture.player_two_name}</td>${playerTwoSets}</tr>
           </table>
           `;
     completedFixturesDiv.append(div);
@@ -72,7 +90,10 @@ function populateResponse(league) {
   for (let fixture of league.uncompleted_fixtures) {
     let div = document.createElement("div");
     div.classList.add("uncompleted-fixture");
-    div.innerHTML = `${fixture.player_one_name} vs ${fixture.player_two_name}`;
+    let round = fixture.round
+      ? `<span class='fixture-round'>Round ${fixture.round}, play by ${fixture.deadline}</span> `
+      : "";
+    div.innerHTML = `${round}${fixture.player_one_name} vs ${fixture.player_two_name}`;
     uncompletedFixturesDiv.append(div);
   }
 }
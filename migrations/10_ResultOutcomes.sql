@@ -0,0 +1,8 @@
+ALTER TABLE fixtures ADD COLUMN outcome VARCHAR(20);
+UPDATE fixtures SET outcome='played' WHERE completed=1;
+
+-- Points for the player who retired, the player who didn't turn up and both
+-- players when neither did
+ALTER TABLE leagues ADD COLUMN points_per_retirement INTEGER DEFAULT 1;
+ALTER TABLE leagues ADD COLUMN points_per_walkover_loss INTEGER DEFAULT 0;
+ALTER TABLE leagues ADD COLUMN points_per_double_walkover INTEGER DEFAULT 0;
@@ -0,0 +1,8 @@
+-- The user account allowed to act for each player
+ALTER TABLE players ADD COLUMN username VARCHAR(50);
+
+-- Player submitted results stay pending until the opponent confirms them
+ALTER TABLE fixtures ADD COLUMN result_status VARCHAR(20);
+ALTER TABLE fixtures ADD COLUMN submitted_by INTEGER;
+ALTER TABLE fixtures ADD COLUMN dispute_reason VARCHAR(500);
+UPDATE fixtures SET result_status='confirmed' WHERE completed=1;
@@ -0,0 +1,20 @@
+-- Single use codes an admin hands to a player so they can claim their record
+CREATE TABLE IF NOT EXISTS player_invites(
+    code VARCHAR(30),
+    player_id INTEGER,
+    created_ts INTEGER,
+    expiry_ts INTEGER,
+    used INTEGER DEFAULT 0,
+    PRIMARY KEY(code)
+);
+
+-- Requests from users to be linked to a player, approved by an admin
+CREATE TABLE IF NOT EXISTS player_link_requests(
+    player_id INTEGER,
+    username VARCHAR(50),
+    status VARCHAR(20) DEFAULT 'pending',
+    created_ts INTEGER
+);
+
+-- An account can only act for one player
+CREATE UNIQUE INDEX IF NOT EXISTS players_username ON players(username);
@@ -0,0 +1,2 @@
+-- The seeded accounts are the existing administrators
+UPDATE users SET auth_level=100 WHERE username IN ('mthalliday','matt.t');
@@ -0,0 +1,17 @@
+-- Outgoing mail waiting to be delivered by the outbox worker. Messages move
+-- from pending to sent, or to dead once they run out of attempts.
+CREATE TABLE IF NOT EXISTS outbox(
+    recipient VARCHAR(300),
+    template VARCHAR(50),
+    subject VARCHAR(300),
+    text_body TEXT,
+    html_body TEXT,
+    status VARCHAR(20) DEFAULT 'pending',
+    attempts INTEGER DEFAULT 0,
+    next_attempt_ts INTEGER,
+    last_error TEXT,
+    created_ts INTEGER,
+    sent_ts INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS outbox_status ON outbox(status,next_attempt_ts);
@@ -0,0 +1,4 @@
+-- Accounts refuse logins until locked_until, and lockouts counts how many
+-- times in a row the account has been locked so each lockout lasts longer
+ALTER TABLE users ADD COLUMN locked_until INTEGER DEFAULT 0;
+ALTER TABLE users ADD COLUMN lockouts INTEGER DEFAULT 0;
@@ -0,0 +1,3 @@
+ALTER TABLE sessions ADD COLUMN created_ts INTEGER;
+ALTER TABLE sessions ADD COLUMN last_seen_ts INTEGER;
+ALTER TABLE sessions ADD COLUMN user_agent VARCHAR(300);
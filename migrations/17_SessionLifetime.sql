@@ -0,0 +1,3 @@
+-- Sessions from before lifetimes were configurable were all long lived
+ALTER TABLE sessions ADD COLUMN remember_me INTEGER DEFAULT 0;
+UPDATE sessions SET remember_me=1;
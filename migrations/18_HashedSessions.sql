@@ -0,0 +1,4 @@
+-- Session keys are now stored as SHA-256 hashes so existing plain text keys
+-- can't be matched and everyone has to log in again
+DELETE FROM sessions;
+ALTER TABLE sessions ADD COLUMN csrf_token VARCHAR;
@@ -0,0 +1,10 @@
+-- Personal tokens for scripts, stored by hash like session keys
+CREATE TABLE IF NOT EXISTS api_tokens (
+    token_hash VARCHAR NOT NULL UNIQUE,
+    username VARCHAR NOT NULL,
+    name VARCHAR NOT NULL,
+    scope VARCHAR NOT NULL,
+    created_ts INTEGER NOT NULL,
+    last_used_ts INTEGER,
+    expiry_ts INTEGER
+);
@@ -0,0 +1,27 @@
+-- Optional TOTP second factor. The last step used is kept so a code can't be replayed.
+ALTER TABLE users ADD COLUMN totp_secret VARCHAR;
+ALTER TABLE users ADD COLUMN totp_enabled INTEGER DEFAULT 0;
+ALTER TABLE users ADD COLUMN totp_last_step INTEGER DEFAULT 0;
+
+-- One-time codes for when the authenticator is lost, stored by hash
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    username VARCHAR NOT NULL,
+    code_hash VARCHAR NOT NULL,
+    used INTEGER DEFAULT 0
+);
+
+-- Logins where the password was right and the second factor is still due
+CREATE TABLE IF NOT EXISTS login_challenges (
+    challenge_hash VARCHAR NOT NULL UNIQUE,
+    username VARCHAR NOT NULL,
+    remember_me INTEGER DEFAULT 0,
+    expiry INTEGER NOT NULL,
+    attempts INTEGER DEFAULT 0
+);
+
+-- Site wide settings changed at runtime by super-admins
+CREATE TABLE IF NOT EXISTS settings (
+    name VARCHAR PRIMARY KEY,
+    value VARCHAR NOT NULL
+);
+INSERT OR IGNORE INTO settings(name,value) values('require_admin_two_factor','false');
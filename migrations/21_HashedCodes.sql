@@ -0,0 +1,3 @@
+-- Codes are now stored as hashes so any issued before can no longer be matched
+DELETE FROM codes;
+CREATE INDEX IF NOT EXISTS codes_email_type ON codes(email, code_type);
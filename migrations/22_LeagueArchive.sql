@@ -0,0 +1,5 @@
+-- Archived leagues keep their history but drop out of listings, fixture
+-- generation and promotion/relegation, freeing their name and tier
+ALTER TABLE leagues ADD COLUMN archived INTEGER DEFAULT 0;
+CREATE UNIQUE INDEX IF NOT EXISTS leagues_active_name ON leagues(league_name) WHERE archived=0;
+CREATE UNIQUE INDEX IF NOT EXISTS leagues_active_tier ON leagues(league_tier) WHERE archived=0;
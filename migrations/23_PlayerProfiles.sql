@@ -0,0 +1,7 @@
+-- Contact details and availability for organisers. Inactive players keep
+-- their history but aren't given fixtures.
+ALTER TABLE players ADD COLUMN email VARCHAR(100);
+ALTER TABLE players ADD COLUMN phone VARCHAR(30);
+ALTER TABLE players ADD COLUMN preferred_hand VARCHAR(20);
+ALTER TABLE players ADD COLUMN availability VARCHAR(500);
+ALTER TABLE players ADD COLUMN active INTEGER DEFAULT 1;
@@ -0,0 +1,3 @@
+-- Sent emails no longer keep their bodies as they can hold login links and
+-- codes. Clear the ones already sent.
+UPDATE outbox SET text_body='', html_body='' WHERE status='sent';
@@ -0,0 +1,2 @@
+-- Invite codes are now stored as hashes so any issued before can no longer be matched
+DELETE FROM player_invites;
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS seasons(
+season_name VARCHAR(100),
+start_date VARCHAR(10),
+end_date VARCHAR(10),
+status VARCHAR(20) DEFAULT 'planned'
+);
+
+-- Existing fixtures were all generated for season 1
+INSERT INTO SEASONS(rowid,season_name,start_date,end_date,status) values(1,'Season 1','2024-10-01','2025-03-31','active');
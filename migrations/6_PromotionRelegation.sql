@@ -0,0 +1,4 @@
+ALTER TABLE leagues ADD COLUMN promotion_places INTEGER DEFAULT 1;
+ALTER TABLE leagues ADD COLUMN relegation_places INTEGER DEFAULT 1;
+
+ALTER TABLE seasons ADD COLUMN movements_applied INTEGER DEFAULT 0;
@@ -0,0 +1,2 @@
+ALTER TABLE fixtures ADD COLUMN round INTEGER;
+ALTER TABLE fixtures ADD COLUMN deadline VARCHAR(10);
@@ -0,0 +1,24 @@
+ALTER TABLE leagues ADD COLUMN match_format VARCHAR(30) DEFAULT 'two_sets_match_tiebreak';
+
+CREATE TABLE IF NOT EXISTS fixture_sets(
+fixture_id INTEGER,
+set_number INTEGER,
+player_one_games INTEGER,
+player_two_games INTEGER,
+PRIMARY KEY(fixture_id,set_number)
+);
+
+-- Move existing two set plus tiebreak scores into the set by set table
+INSERT INTO fixture_sets SELECT rowid,1,player_one_set_one_games,player_two_set_one_games FROM fixtures
+WHERE player_one_set_one_games IS NOT NULL AND player_two_set_one_games IS NOT NULL;
+INSERT INTO fixture_sets SELECT rowid,2,player_one_set_two_games,player_two_set_two_games FROM fixtures
+WHERE player_one_set_two_games IS NOT NULL AND player_two_set_two_games IS NOT NULL;
+INSERT INTO fixture_sets SELECT rowid,3,player_one_tiebreak_points,player_two_tiebreak_points FROM fixtures
+WHERE player_one_tiebreak_points IS NOT NULL AND player_two_tiebreak_points IS NOT NULL;
+
+ALTER TABLE fixtures DROP COLUMN player_one_set_one_games;
+ALTER TABLE fixtures DROP COLUMN player_two_set_one_games;
+ALTER TABLE fixtures DROP COLUMN player_one_set_two_games;
+ALTER TABLE fixtures DROP COLUMN player_two_set_two_games;
+ALTER TABLE fixtures DROP COLUMN player_one_tiebreak_points;
+ALTER TABLE fixtures DROP COLUMN player_two_tiebreak_points;
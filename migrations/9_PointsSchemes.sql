@@ -0,0 +1,6 @@
+-- Defaults match the original scoring of a point per match played plus a point per set won
+ALTER TABLE leagues ADD COLUMN points_per_win INTEGER DEFAULT 1;
+ALTER TABLE leagues ADD COLUMN points_per_loss INTEGER DEFAULT 1;
+ALTER TABLE leagues ADD COLUMN points_per_set INTEGER DEFAULT 1;
+ALTER TABLE leagues ADD COLUMN points_per_walkover INTEGER DEFAULT 3;
+ALTER TABLE leagues ADD COLUMN tie_breakers VARCHAR(100) DEFAULT 'head_to_head,set_difference,game_difference,games_won';
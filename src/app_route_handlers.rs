@@ -1,23 +1,96 @@
-use crate::{default_route_handlers::AppError, AppState};
-use axum::extract::{Json, Path, State};
+use crate::{
+    auth::hash_token,
+    default_route_handlers::{validations::validate_email, AppError, ErrorList, User},
+    email::{queue_email, EmailTemplate},
+    utilities::{double_option, generate_unique_id},
+    AppState,
+};
+use axum::extract::{Json, Path, Query, State};
+use chrono::{NaiveDate, Utc};
 use http::StatusCode;
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
-use sqlx::Row;
-use std::collections::HashMap;
+use sqlx::{Row, Sqlite, Transaction};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
 
-const SEASON: i8 = 1;
+pub mod promotion;
+pub mod scheduling;
+pub mod scoring;
+pub mod standings;
+
+use promotion::{plan_movements, LeagueStandings, PlayerMovement};
+use scoring::{MatchFormat, ResultOutcome, SetScore, Side};
+use standings::{compute_league_table, LeagueTableRow, PointsScheme, PointsSchemeRow};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum PreferredHand {
+    Left,
+    Right,
+    Ambidextrous,
+}
 
 #[derive(Deserialize)]
 pub struct NewPlayerRequest {
     name: String,
     league_id: i64,
+    email: Option<String>,
+    phone: Option<String>,
+    preferred_hand: Option<PreferredHand>,
+    availability: Option<String>,
+}
+
+// Changes only the fields given. The profile details are cleared by giving
+// null, or an empty string for the text ones.
+#[derive(Deserialize)]
+pub struct UpdatePlayerRequest {
+    name: Option<String>,
+    league_id: Option<i64>,
+    #[serde(default, deserialize_with = "double_option")]
+    email: Option<Option<String>>,
+    #[serde(default, deserialize_with = "double_option")]
+    phone: Option<Option<String>>,
+    #[serde(default, deserialize_with = "double_option")]
+    preferred_hand: Option<Option<PreferredHand>>,
+    #[serde(default, deserialize_with = "double_option")]
+    availability: Option<Option<String>>,
+    active: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct PlayersQuery {
+    // Matches part of the player's name or email
+    search: Option<String>,
+    league_id: Option<i64>,
+    active: Option<bool>,
 }
 
 #[derive(Deserialize)]
 pub struct NewLeagueRequest {
     name: String,
+    tier: i64,
+    #[serde(default)]
+    match_format: MatchFormat,
+}
+
+// Renames and/or re-tiers a league, leaving out whichever isn't changing
+#[derive(Deserialize)]
+pub struct UpdateLeagueRequest {
+    name: Option<String>,
+    tier: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveLeagueRequest {
+    archived: bool,
+}
+
+#[derive(Deserialize)]
+pub struct LeaguesQuery {
+    #[serde(default)]
+    include_archived: bool,
 }
 
 #[derive(Deserialize)]
@@ -26,434 +99,1963 @@ pub struct AmendPlayerRequest {
     new_league_id: i64,
 }
 
+#[derive(Deserialize)]
+pub struct NewSeasonRequest {
+    season_name: String,
+    start_date: String,
+    end_date: String,
+}
+
+#[derive(Deserialize)]
+pub struct ActivateSeasonRequest {
+    season_id: i64,
+}
+
+#[derive(Deserialize)]
+pub struct LeagueFormatRequest {
+    match_format: MatchFormat,
+}
+
+#[derive(Deserialize)]
+pub struct ResultSubmission {
+    season: Option<i64>,
+    league_id: i64,
+    player_one_id: i64,
+    player_two_id: i64,
+    #[serde(default)]
+    outcome: ResultOutcome,
+    // Only needed for retirements and walkovers, otherwise taken from the score
+    winner: Option<i64>,
+    #[serde(default)]
+    sets: Vec<SetScore>,
+    completed: i8,
+}
+
+#[derive(Deserialize)]
+pub struct DisputeRequest {
+    reason: String,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DisputeResolution {
+    // Accept the result as the player submitted it
+    Confirm,
+    // Throw the result away so the fixture can be played or submitted again
+    Void,
+}
+
+#[derive(Deserialize)]
+pub struct DisputeResolutionRequest {
+    resolution: DisputeResolution,
+}
+
+#[derive(Deserialize)]
+pub struct LinkPlayerRequest {
+    username: String,
+}
+
+#[derive(Deserialize)]
+pub struct ClaimInviteRequest {
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct LinkRequest {
+    player_id: i64,
+}
+
+#[derive(Deserialize)]
+pub struct LinkRequestDecision {
+    approve: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ReminderQuery {
+    days: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct LeagueMovementRequest {
+    promotion_places: i64,
+    relegation_places: i64,
+}
+
+// Optional season selector, defaults to the active season when absent
+#[derive(Deserialize)]
+pub struct SeasonQuery {
+    season_id: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum SeasonStatus {
+    Planned,
+    Active,
+    Finished,
+}
+
+// Only confirmed results count towards the league table
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum ResultStatus {
+    Pending,
+    Disputed,
+    Confirmed,
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct Season {
+    #[sqlx(rename = "rowid")]
+    season_id: i64,
+    season_name: String,
+    start_date: String,
+    end_date: String,
+    status: SeasonStatus,
+}
+
 #[derive(Serialize, Deserialize, FromRow)]
 pub struct League {
     #[sqlx(rename = "rowid")]
     league_id: i64,
     league_name: String,
     league_tier: i64,
+    match_format: MatchFormat,
+    archived: bool,
+}
+
+#[derive(Deserialize, FromRow, Serialize)]
+pub struct LeagueDetails {
+    #[sqlx(rename = "rowid")]
+    league_id: i64,
+    league_name: String,
+    league_tier: i64,
+    match_format: MatchFormat,
+    archived: bool,
+    promotion_places: i64,
+    relegation_places: i64,
+    player_count: i64,
 }
 
 #[derive(Deserialize, FromRow, Serialize)]
 pub struct MatchResult {
+    #[sqlx(rename = "rowid")]
+    fixture_id: i64,
     season: i64,
     league_id: i64,
     player_one_id: i64,
     player_two_id: i64,
     player_one_name: Option<String>,
     player_two_name: Option<String>,
-    player_one_set_one_games: i8,
-    player_two_set_one_games: i8,
-    player_one_set_two_games: i8,
-    player_two_set_two_games: i8,
-    player_one_tiebreak_points: Option<i8>,
-    player_two_tiebreak_points: Option<i8>,
+    round: Option<i64>,
+    deadline: Option<String>,
+    #[sqlx(skip)]
+    sets: Vec<SetScore>,
+    outcome: Option<ResultOutcome>,
     completed: i8,
     winner: Option<i64>,
+    result_status: Option<ResultStatus>,
+    // The player who submitted a result that still needs confirming
+    submitted_by: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct LeagueTableAndFixtures {
-    league_table: Vec<LeagueTableRow>,
-    completed_fixtures: Vec<MatchResult>,
-    uncompleted_fixtures: Vec<MatchResult>,
+pub struct DisputedResult {
+    #[serde(flatten)]
+    fixture: MatchResult,
+    dispute_reason: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
-struct LeagueTableRow {
-    name: String,
-    played: i8,
-    matches_won: i8,
-    matches_lost: i8,
-    sets_won: i8,
-    sets_lost: i8,
-    games_won: i8,
-    games_lost: i8,
-    points: i8,
-}
-
-impl LeagueTableRow {
-    pub fn new(name: String) -> Self {
-        Self {
-            name,
-            played: 0,
-            matches_won: 0,
-            matches_lost: 0,
-            sets_won: 0,
-            sets_lost: 0,
-            games_won: 0,
-            games_lost: 0,
-            points: 0,
-        }
-    }
+pub struct PlayerInvite {
+    code: String,
+    player_id: i64,
+    expiry_ts: i64,
 }
 
-pub async fn create_player(
-    State(state): State<Arc<AppState>>,
-    Json(player): Json<NewPlayerRequest>,
-) -> Result<StatusCode, AppError> {
-    sqlx::query("INSERT INTO PLAYERS(name,league_id) values(?,?) RETURNING rowid")
-        .bind(player.name)
-        .bind(player.league_id)
-        .execute(&state.db_connection_pool)
-        .await?;
-    Ok(StatusCode::NO_CONTENT)
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct PlayerLinkRequest {
+    #[sqlx(rename = "rowid")]
+    request_id: i64,
+    player_id: i64,
+    player_name: String,
+    username: String,
+    created_ts: i64,
 }
 
-pub async fn create_league(
-    State(state): State<Arc<AppState>>,
-    Json(league): Json<NewLeagueRequest>,
-) -> Result<StatusCode, AppError> {
-    sqlx::query("INSERT INTO LEAGUES(name) values(?) RETURNING rowid")
-        .bind(league.name)
-        .execute(&state.db_connection_pool)
-        .await?;
-    Ok(StatusCode::NO_CONTENT)
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct PlayerProfile {
+    #[sqlx(rename = "rowid")]
+    player_id: i64,
+    name: String,
+    league_id: Option<i64>,
+    league_name: Option<String>,
+    // The user account linked to the player, if any
+    username: Option<String>,
+    email: Option<String>,
+    phone: Option<String>,
+    preferred_hand: Option<PreferredHand>,
+    availability: Option<String>,
+    active: bool,
 }
 
-pub async fn add_player_to_league(
-    State(state): State<Arc<AppState>>,
-    Json(player): Json<AmendPlayerRequest>,
-) -> Result<StatusCode, AppError> {
-    sqlx::query("UPDATE PLAYERS SET league_id=? WHERE rowid=?")
-        .bind(player.new_league_id)
-        .bind(player.player_id)
-        .execute(&state.db_connection_pool)
-        .await?;
-    Ok(StatusCode::NO_CONTENT)
+const PLAYER_SELECT: &str = "SELECT p.rowid,p.name,p.league_id,l.league_name,p.username,
+    p.email,p.phone,p.preferred_hand,p.availability,p.active
+    FROM players p
+    LEFT JOIN leagues l ON l.rowid = p.league_id";
+
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct LinkedPlayer {
+    #[sqlx(rename = "rowid")]
+    player_id: i64,
+    name: String,
+    league_id: i64,
+    league_name: Option<String>,
 }
 
-pub async fn generate_fixtures(State(state): State<Arc<AppState>>) -> Result<StatusCode, AppError> {
-    let leagues = sqlx::query("SELECT rowid FROM leagues")
-        .fetch_all(&state.db_connection_pool)
-        .await?;
+// The logged in user with their player record and fixtures for the active season
+#[derive(Serialize, Deserialize)]
+pub struct Me {
+    username: String,
+    email: String,
+    player: Option<LinkedPlayer>,
+    season_id: Option<i64>,
+    upcoming_fixtures: Vec<MatchResult>,
+    completed_fixtures: Vec<MatchResult>,
+}
 
-    let league_ids: Vec<i32> = leagues.into_iter().map(|x| x.get(0)).collect();
+#[derive(Serialize, Deserialize)]
+pub struct ReminderSummary {
+    fixtures: usize,
+    reminders_queued: usize,
+}
 
-    for league_id in league_ids {
-        let league_players = sqlx::query("SELECT rowid FROM players WHERE league_id=?")
-            .bind(league_id)
-            .fetch_all(&state.db_connection_pool)
-            .await?;
+// Who is playing in a fixture and where its result is up to
+#[derive(FromRow)]
+struct FixtureParticipants {
+    player_one_id: i64,
+    player_two_id: i64,
+    result_status: Option<ResultStatus>,
+    submitted_by: Option<i64>,
+}
 
-        let player_ids: Vec<i64> = league_players.into_iter().map(|x| x.get(0)).collect();
+#[derive(FromRow)]
+struct FixtureSet {
+    fixture_id: i64,
+    player_one_games: i64,
+    player_two_games: i64,
+}
 
-        let mut i = 0;
-        let mut j = 1;
+#[derive(Serialize, Deserialize)]
+pub struct LeagueTableAndFixtures {
+    match_format: MatchFormat,
+    max_sets: usize,
+    points_scheme: PointsScheme,
+    league_table: Vec<LeagueTableRow>,
+    completed_fixtures: Vec<MatchResult>,
+    uncompleted_fixtures: Vec<MatchResult>,
+    rounds: Vec<FixtureRound>,
+}
 
-        while i < player_ids.len() {
-            while j < player_ids.len() {
-                sqlx::query("INSERT INTO fixtures (season,league_id,player_one_id,player_two_id) values(?,?,?,?)")
-                .bind(SEASON)
-                .bind(league_id)
-                .bind(player_ids[i])
-                .bind(player_ids[j])
-                .execute(&state.db_connection_pool)
-                .await?;
-                j += 1;
-            }
-            i += 1;
-            j = i + 1;
-        }
-    }
+#[derive(Serialize, Deserialize)]
+pub struct FixtureGenerationSummary {
+    season_id: i64,
+    leagues: Vec<LeagueFixtureSummary>,
+}
 
-    Ok(StatusCode::NO_CONTENT)
+#[derive(Serialize, Deserialize)]
+pub struct LeagueFixtureSummary {
+    league_id: i64,
+    league_name: String,
+    fixtures_created: usize,
+    fixtures_skipped: usize,
+    rounds_created: usize,
 }
 
-pub async fn put_result(
-    State(state): State<Arc<AppState>>,
-    Json(match_result): Json<MatchResult>,
-) -> Result<StatusCode, AppError> {
-    let mut winner: Option<i64> = None;
-    let mut p1_sets = 0;
-    let mut p2_sets = 0;
-    if match_result.completed == 1 {
-        if match_result.player_one_set_one_games > match_result.player_two_set_one_games {
-            p1_sets += 1;
-        } else {
-            p2_sets += 1;
-        }
+#[derive(Serialize, Deserialize)]
+pub struct FixtureRound {
+    round: i64,
+    deadline: Option<String>,
+    fixtures: i64,
+    completed_fixtures: i64,
+}
 
-        if match_result.player_one_set_two_games > match_result.player_two_set_two_games {
-            p1_sets += 1;
-        } else {
-            p2_sets += 1;
-        }
-        if match_result.player_one_tiebreak_points.is_some()
-            && match_result.player_two_tiebreak_points.is_some()
-        {
-            if match_result.player_one_tiebreak_points > match_result.player_two_tiebreak_points {
-                p1_sets += 1;
-            } else {
-                p2_sets += 1;
-            }
-        }
-    }
+#[derive(Serialize, Deserialize)]
+pub struct PromotionRelegation {
+    season_id: i64,
+    applied: bool,
+    movements: Vec<PlayerMovement>,
+}
 
-    if p1_sets == 2 {
-        winner = Some(match_result.player_one_id);
-    } else if p2_sets == 2 {
-        winner = Some(match_result.player_two_id);
-    }
-    sqlx::query(
-        "UPDATE FIXTURES SET
-        player_one_set_one_games=?,
-        player_one_set_two_games=?,
-        player_two_set_one_games=?,
-        player_two_set_two_games=?,
-        player_one_tiebreak_points=?,
-        player_two_tiebreak_points=?,
-        completed=?,
-        winner=?
-        WHERE
-        season=? and
-        league_id=? and
-        player_one_id=? and
-        player_two_id=?
-        ",
-    )
-    .bind(match_result.player_one_set_one_games)
-    .bind(match_result.player_one_set_two_games)
-    .bind(match_result.player_two_set_one_games)
-    .bind(match_result.player_two_set_two_games)
-    .bind(match_result.player_one_tiebreak_points)
-    .bind(match_result.player_two_tiebreak_points)
-    .bind(match_result.completed)
-    .bind(winner)
-    .bind(match_result.season)
-    .bind(match_result.league_id)
-    .bind(match_result.player_one_id)
-    .bind(match_result.player_two_id)
-    .execute(&state.db_connection_pool)
-    .await?;
-    Ok(StatusCode::RESET_CONTENT)
+#[derive(FromRow)]
+struct LeagueMovementPlaces {
+    #[sqlx(rename = "rowid")]
+    league_id: i64,
+    promotion_places: i64,
+    relegation_places: i64,
+    match_format: MatchFormat,
 }
 
-pub async fn generate_league_table(
-    Path(league_id): Path<i64>,
+pub async fn create_player(
     State(state): State<Arc<AppState>>,
-) -> Result<Json<LeagueTableAndFixtures>, AppError> {
-    let player_map = get_player_map(state.clone()).await?;
+    Json(player): Json<NewPlayerRequest>,
+) -> Result<(StatusCode, Json<PlayerProfile>), AppError> {
+    let name = validate_player_name(&player.name)?;
+    check_league_open(player.league_id, state.clone()).await?;
+    validate_contact_details(player.email.as_deref(), player.phone.as_deref())?;
 
-    let uncompleted_fixtures = sqlx::query_as::<_, MatchResult>(
-        "SELECT
-        season,
-        f.league_id,
-        player_one_id,
-        player_two_id,
-        player_one_set_one_games,
-        player_one_set_two_games,
-        player_two_set_one_games,
-        player_two_set_two_games,
-        player_one_tiebreak_points,
-        player_two_tiebreak_points,
-        completed,
-        winner,
-        p1.name as 'player_one_name',
-        p2.name as 'player_two_name'
-        FROM fixtures f
-        join players p1 on p1.rowid = player_one_id
-        join players p2 on p2.rowid = player_two_id
-        WHERE f.league_id=? and completed=0",
+    let player_id = sqlx::query(
+        "INSERT INTO players(name,league_id,email,phone,preferred_hand,availability)
+        values(?,?,NULLIF(?, ''),NULLIF(?, ''),?,NULLIF(?, ''))",
     )
-    .bind(league_id)
-    .fetch_all(&state.db_connection_pool)
-    .await?;
+    .bind(name)
+    .bind(player.league_id)
+    .bind(player.email.as_deref().map(str::trim))
+    .bind(player.phone.as_deref().map(str::trim))
+    .bind(player.preferred_hand)
+    .bind(player.availability.as_deref().map(str::trim))
+    .execute(&state.db_connection_pool)
+    .await?
+    .last_insert_rowid();
+    Ok((
+        StatusCode::CREATED,
+        Json(get_player_profile(player_id, state).await?),
+    ))
+}
 
-    let completed_fixtures = sqlx::query_as::<_, MatchResult>(
-        "SELECT
-        season,
-        f.league_id,
-        player_one_id,
-        player_two_id,
-        player_one_set_one_games,
-        player_one_set_two_games,
-        player_two_set_one_games,
-        player_two_set_two_games,
-        player_one_tiebreak_points,
-        player_two_tiebreak_points,
-        completed,
-        winner,
-        p1.name as 'player_one_name',
-        p2.name as 'player_two_name'
-        FROM fixtures f
-        join players p1 on p1.rowid = player_one_id
-        join players p2 on p2.rowid = player_two_id
-        WHERE f.league_id=? and completed=1",
-    )
-    .bind(league_id)
+pub async fn get_players(
+    State(state): State<Arc<AppState>>,
+    Query(players_query): Query<PlayersQuery>,
+) -> Result<Json<Vec<PlayerProfile>>, AppError> {
+    // Wildcards typed into the search are matched literally
+    let search = players_query.search.map(|search| {
+        let escaped = search
+            .trim()
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        format!("%{}%", escaped)
+    });
+    let players = sqlx::query_as::<_, PlayerProfile>(&format!(
+        "{} WHERE (? IS NULL OR p.name LIKE ? ESCAPE '\\' OR p.email LIKE ? ESCAPE '\\')
+        AND (? IS NULL OR p.league_id=?)
+        AND (? IS NULL OR p.active=?)
+        ORDER BY p.name",
+        PLAYER_SELECT
+    ))
+    .bind(&search)
+    .bind(&search)
+    .bind(&search)
+    .bind(players_query.league_id)
+    .bind(players_query.league_id)
+    .bind(players_query.active)
+    .bind(players_query.active)
     .fetch_all(&state.db_connection_pool)
     .await?;
+    Ok(Json(players))
+}
 
-    let league_players = get_league_players(league_id, state).await?;
-
-    let league_table = compute_league_table(league_players, player_map, &completed_fixtures).await;
-
-    let league_table_and_fixtures = LeagueTableAndFixtures {
-        completed_fixtures,
-        uncompleted_fixtures,
-        league_table,
-    };
-
-    Ok(Json(league_table_and_fixtures))
+pub async fn get_player(
+    Path(player_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<PlayerProfile>, AppError> {
+    Ok(Json(get_player_profile(player_id, state).await?))
 }
 
-pub async fn get_leagues(
+pub async fn update_player(
+    Path(player_id): Path<i64>,
     State(state): State<Arc<AppState>>,
-) -> Result<Json<Vec<League>>, AppError> {
-    let leagues: Vec<League> =
-        sqlx::query_as::<_, League>("SELECT rowid,league_name, league_tier FROM leagues")
-            .fetch_all(&state.db_connection_pool)
-            .await?;
-    Ok(Json(leagues))
+    Json(update): Json<UpdatePlayerRequest>,
+) -> Result<Json<PlayerProfile>, AppError> {
+    get_player_profile(player_id, state.clone()).await?;
+    let name = update
+        .name
+        .as_deref()
+        .map(validate_player_name)
+        .transpose()?;
+    if let Some(league_id) = update.league_id {
+        check_league_open(league_id, state.clone()).await?;
+    }
+    let email = update
+        .email
+        .as_ref()
+        .map(|email| email.as_deref().map(str::trim));
+    let phone = update
+        .phone
+        .as_ref()
+        .map(|phone| phone.as_deref().map(str::trim));
+    let availability = update
+        .availability
+        .as_ref()
+        .map(|availability| availability.as_deref().map(str::trim));
+    validate_contact_details(email.flatten(), phone.flatten())?;
+
+    // Each detail is only set when it was given, which may be to null
+    sqlx::query(
+        "UPDATE players SET
+        name=COALESCE(?, name),
+        league_id=COALESCE(?, league_id),
+        email=CASE WHEN ? THEN NULLIF(?, '') ELSE email END,
+        phone=CASE WHEN ? THEN NULLIF(?, '') ELSE phone END,
+        preferred_hand=CASE WHEN ? THEN ? ELSE preferred_hand END,
+        availability=CASE WHEN ? THEN NULLIF(?, '') ELSE availability END,
+        active=COALESCE(?, active)
+        WHERE rowid=?",
+    )
+    .bind(name)
+    .bind(update.league_id)
+    .bind(email.is_some())
+    .bind(email.flatten())
+    .bind(phone.is_some())
+    .bind(phone.flatten())
+    .bind(update.preferred_hand.is_some())
+    .bind(update.preferred_hand.flatten())
+    .bind(availability.is_some())
+    .bind(availability.flatten())
+    .bind(update.active)
+    .bind(player_id)
+    .execute(&state.db_connection_pool)
+    .await?;
+    Ok(Json(get_player_profile(player_id, state).await?))
 }
 
-// Functions below this point are not routes and should be moved elsewhere
+// Players with fixtures are part of the record so they can only be made
+// inactive. Their outstanding invites and link requests go with them.
+pub async fn delete_player(
+    Path(player_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+) -> Result<StatusCode, AppError> {
+    get_player_profile(player_id, state.clone()).await?;
+    let has_fixtures =
+        sqlx::query("SELECT 1 FROM fixtures WHERE player_one_id=? OR player_two_id=? LIMIT 1")
+            .bind(player_id)
+            .bind(player_id)
+            .fetch_optional(&state.db_connection_pool)
+            .await?
+            .is_some();
+    if has_fixtures {
+        return Err(ErrorList::PlayerHasFixtures.into());
+    }
 
-async fn get_player_map(state: Arc<AppState>) -> Result<HashMap<i64, String>, anyhow::Error> {
-    let players = sqlx::query("SELECT rowid,name FROM players")
-        .fetch_all(&state.db_connection_pool)
+    let mut transaction = state.db_connection_pool.begin().await?;
+    sqlx::query("DELETE FROM player_invites WHERE player_id=?")
+        .bind(player_id)
+        .execute(&mut *transaction)
         .await?;
-    let mut players_map: HashMap<i64, String> = HashMap::new();
+    sqlx::query("DELETE FROM player_link_requests WHERE player_id=?")
+        .bind(player_id)
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query("DELETE FROM players WHERE rowid=?")
+        .bind(player_id)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    for player in players {
-        players_map.insert(player.get(0), player.get(1));
+fn validate_player_name(name: &str) -> Result<String, ErrorList> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > 100 {
+        return Err(ErrorList::InvalidPlayerName);
     }
-    Ok(players_map)
+    Ok(name.to_string())
 }
 
-async fn compute_league_table(
-    league_players: Vec<i64>,
-    player_map: HashMap<i64, String>,
-    completed_fixures: &Vec<MatchResult>,
-) -> Vec<LeagueTableRow> {
-    let mut league_table = vec![];
-    for player_id in league_players {
-        let mut row = LeagueTableRow::new(
-            player_map
-                .get(&player_id)
-                .expect("Player not found in map")
-                .to_owned(),
-        );
-        // Loop through fixtures
-        for fixture in completed_fixures {
-            let mut match_sets = 0;
-            let mut involved = false;
-            if fixture.player_one_id == player_id {
-                involved = true;
-                // Match logic
-                row.points += 1;
-                // Set 1 logic
-                // Won set
-                if fixture.player_one_set_one_games > fixture.player_two_set_one_games {
-                    row.sets_won += 1;
-                    match_sets += 1;
-                    row.points += 1;
-                }
-                // Lost set
-                else if fixture.player_one_set_one_games < fixture.player_two_set_one_games {
-                    row.sets_lost += 1;
-                }
-                row.games_won += fixture.player_one_set_one_games;
-                row.games_lost += fixture.player_two_set_one_games;
-                // Set 2 logic
-                // Won set
-                if fixture.player_one_set_two_games > fixture.player_two_set_two_games {
-                    row.sets_won += 1;
-                    match_sets += 1;
-                    row.points += 1;
-                }
-                // Lost set
-                else if fixture.player_one_set_two_games < fixture.player_two_set_two_games {
-                    row.sets_lost += 1;
-                }
-                row.games_won += fixture.player_one_set_two_games;
-                row.games_lost += fixture.player_two_set_two_games;
-                // Tiebreak if applicable
-                if fixture.player_one_tiebreak_points.is_some()
-                    && fixture.player_two_tiebreak_points.is_some()
-                {
-                    if fixture.player_one_tiebreak_points.unwrap()
-                        > fixture.player_two_tiebreak_points.unwrap()
-                    {
-                        row.sets_won += 1;
-                        match_sets += 1;
-                        row.points += 1;
-                    } else if fixture.player_one_tiebreak_points.unwrap()
-                        < fixture.player_two_tiebreak_points.unwrap()
-                    {
-                        row.sets_lost += 1;
-                    }
-                }
-            } else if fixture.player_two_id == player_id {
-                involved = true;
-                // Match logic
-                row.points += 1;
-                // Set 1 logic
-                // Won set
-                if fixture.player_two_set_one_games > fixture.player_one_set_one_games {
-                    row.sets_won += 1;
-                    match_sets += 1;
-                    row.points += 1;
-                }
-                // Lost set
-                else if fixture.player_two_set_one_games < fixture.player_one_set_one_games {
-                    row.sets_lost += 1;
-                }
-                row.games_won += fixture.player_two_set_one_games;
-                row.games_lost += fixture.player_one_set_one_games;
-
-                // Set 2 logic
-                // Won set
-                if fixture.player_two_set_two_games > fixture.player_one_set_two_games {
-                    row.sets_won += 1;
-                    match_sets += 1;
-                    row.points += 1;
-                }
-                // Lost set
-                else if fixture.player_two_set_two_games < fixture.player_one_set_two_games {
-                    row.sets_lost += 1;
-                }
-                row.games_won += fixture.player_two_set_two_games;
-                row.games_lost += fixture.player_one_set_two_games;
-                // Tiebreak if applicable
-                if fixture.player_one_tiebreak_points.is_some()
-                    && fixture.player_two_tiebreak_points.is_some()
-                {
-                    if fixture.player_one_tiebreak_points.unwrap()
-                        < fixture.player_two_tiebreak_points.unwrap()
-                    {
-                        row.sets_won += 1;
-                        match_sets += 1;
-                        row.points += 1;
-                    } else if fixture.player_one_tiebreak_points.unwrap()
-                        > fixture.player_two_tiebreak_points.unwrap()
-                    {
-                        row.sets_lost += 1;
-                    }
-                }
-            }
-            if match_sets == 2 {
-                row.matches_won += 1;
-            } else if involved {
-                row.matches_lost += 1;
-            }
+// Empty values are allowed as they clear the detail
+fn validate_contact_details(email: Option<&str>, phone: Option<&str>) -> Result<(), ErrorList> {
+    if let Some(email) = email.map(str::trim).filter(|email| !email.is_empty()) {
+        validate_email(email)?;
+    }
+    if let Some(phone) = phone.map(str::trim).filter(|phone| !phone.is_empty()) {
+        let valid = phone.len() <= 30
+            && phone.chars().filter(|c| c.is_ascii_digit()).count() >= 6
+            && phone
+                .chars()
+                .all(|c| c.is_ascii_digit() || " +-()".contains(c));
+        if !valid {
+            return Err(ErrorList::InvalidPhoneNumber);
         }
-        row.played = row.matches_won + row.matches_lost;
-        league_table.push(row);
     }
-    league_table.sort_by(|a, b| b.points.cmp(&a.points));
-    league_table
+    Ok(())
 }
 
-async fn get_league_players(
+// Players can only be put in leagues that exist and aren't archived
+async fn check_league_open(league_id: i64, state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let archived: Option<bool> = sqlx::query_scalar("SELECT archived FROM leagues WHERE rowid=?")
+        .bind(league_id)
+        .fetch_optional(&state.db_connection_pool)
+        .await?;
+    match archived {
+        None => Err(ErrorList::LeagueNotFound.into()),
+        Some(true) => Err(ErrorList::LeagueArchived.into()),
+        Some(false) => Ok(()),
+    }
+}
+
+async fn get_player_profile(
+    player_id: i64,
+    state: Arc<AppState>,
+) -> Result<PlayerProfile, anyhow::Error> {
+    let player = sqlx::query_as::<_, PlayerProfile>(&format!("{} WHERE p.rowid=?", PLAYER_SELECT))
+        .bind(player_id)
+        .fetch_optional(&state.db_connection_pool)
+        .await?;
+    player.ok_or(ErrorList::PlayerNotFound.into())
+}
+
+pub async fn create_league(
+    State(state): State<Arc<AppState>>,
+    Json(league): Json<NewLeagueRequest>,
+) -> Result<(StatusCode, Json<LeagueDetails>), AppError> {
+    let name = validate_league_name(&league.name)?;
+    validate_league_tier(league.tier)?;
+    check_league_unique(None, Some(&name), Some(league.tier), state.clone()).await?;
+
+    let league_id =
+        sqlx::query("INSERT INTO leagues(league_name,league_tier,match_format) values(?,?,?)")
+            .bind(&name)
+            .bind(league.tier)
+            .bind(league.match_format)
+            .execute(&state.db_connection_pool)
+            .await?
+            .last_insert_rowid();
+    Ok((
+        StatusCode::CREATED,
+        Json(get_league_details(league_id, state).await?),
+    ))
+}
+
+pub async fn get_league(
+    Path(league_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<LeagueDetails>, AppError> {
+    Ok(Json(get_league_details(league_id, state).await?))
+}
+
+pub async fn update_league(
+    Path(league_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Json(update): Json<UpdateLeagueRequest>,
+) -> Result<Json<LeagueDetails>, AppError> {
+    let league = get_league_details(league_id, state.clone()).await?;
+    let name = update
+        .name
+        .as_deref()
+        .map(validate_league_name)
+        .transpose()?;
+    if let Some(tier) = update.tier {
+        validate_league_tier(tier)?;
+    }
+    // Archived leagues don't hold on to their name or tier
+    if !league.archived {
+        check_league_unique(Some(league_id), name.as_deref(), update.tier, state.clone()).await?;
+    }
+
+    sqlx::query(
+        "UPDATE leagues SET league_name=COALESCE(?, league_name), league_tier=COALESCE(?, league_tier)
+        WHERE rowid=?",
+    )
+    .bind(name)
+    .bind(update.tier)
+    .bind(league_id)
+    .execute(&state.db_connection_pool)
+    .await?;
+    Ok(Json(get_league_details(league_id, state).await?))
+}
+
+// Archiving needs the league emptied first so nobody is left without fixtures
+pub async fn archive_league(
+    Path(league_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Json(archive): Json<ArchiveLeagueRequest>,
+) -> Result<Json<LeagueDetails>, AppError> {
+    let league = get_league_details(league_id, state.clone()).await?;
+    if archive.archived && league.player_count > 0 {
+        return Err(ErrorList::LeagueHasPlayers.into());
+    }
+    if !archive.archived && league.archived {
+        check_league_unique(
+            Some(league_id),
+            Some(&league.league_name),
+            Some(league.league_tier),
+            state.clone(),
+        )
+        .await?;
+    }
+
+    sqlx::query("UPDATE leagues SET archived=? WHERE rowid=?")
+        .bind(archive.archived)
+        .bind(league_id)
+        .execute(&state.db_connection_pool)
+        .await?;
+    Ok(Json(get_league_details(league_id, state).await?))
+}
+
+// Only leagues that were never used can be deleted, anything with history
+// should be archived instead
+pub async fn delete_league(
+    Path(league_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+) -> Result<StatusCode, AppError> {
+    let league = get_league_details(league_id, state.clone()).await?;
+    if league.player_count > 0 {
+        return Err(ErrorList::LeagueHasPlayers.into());
+    }
+    let has_fixtures = sqlx::query("SELECT 1 FROM fixtures WHERE league_id=? LIMIT 1")
+        .bind(league_id)
+        .fetch_optional(&state.db_connection_pool)
+        .await?
+        .is_some();
+    if has_fixtures {
+        return Err(ErrorList::LeagueHasFixtures.into());
+    }
+
+    sqlx::query("DELETE FROM leagues WHERE rowid=?")
+        .bind(league_id)
+        .execute(&state.db_connection_pool)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn validate_league_name(name: &str) -> Result<String, ErrorList> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > 100 {
+        return Err(ErrorList::InvalidLeagueName);
+    }
+    Ok(name.to_string())
+}
+
+// Tier 1 is the top league
+fn validate_league_tier(tier: i64) -> Result<(), ErrorList> {
+    if tier < 1 {
+        return Err(ErrorList::InvalidLeagueTier);
+    }
+    Ok(())
+}
+
+// Names and tiers must be unique among leagues that aren't archived
+async fn check_league_unique(
+    league_id: Option<i64>,
+    name: Option<&str>,
+    tier: Option<i64>,
+    state: Arc<AppState>,
+) -> Result<(), anyhow::Error> {
+    if let Some(name) = name {
+        let taken = sqlx::query(
+            "SELECT 1 FROM leagues WHERE league_name=? COLLATE NOCASE AND archived=0 AND rowid IS NOT ?",
+        )
+        .bind(name)
+        .bind(league_id)
+        .fetch_optional(&state.db_connection_pool)
+        .await?;
+        if taken.is_some() {
+            return Err(ErrorList::LeagueNameTaken.into());
+        }
+    }
+    if let Some(tier) = tier {
+        let taken = sqlx::query(
+            "SELECT 1 FROM leagues WHERE league_tier=? AND archived=0 AND rowid IS NOT ?",
+        )
+        .bind(tier)
+        .bind(league_id)
+        .fetch_optional(&state.db_connection_pool)
+        .await?;
+        if taken.is_some() {
+            return Err(ErrorList::LeagueTierTaken.into());
+        }
+    }
+    Ok(())
+}
+
+async fn get_league_details(
     league_id: i64,
     state: Arc<AppState>,
-) -> Result<Vec<i64>, anyhow::Error> {
-    let league_players = sqlx::query("SELECT rowid FROM players WHERE league_id=?")
+) -> Result<LeagueDetails, anyhow::Error> {
+    let league = sqlx::query_as::<_, LeagueDetails>(
+        "SELECT rowid,league_name,league_tier,match_format,archived,promotion_places,relegation_places,
+        (SELECT COUNT(*) FROM players WHERE players.league_id=leagues.rowid) AS player_count
+        FROM leagues WHERE rowid=?",
+    )
+    .bind(league_id)
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+    league.ok_or(ErrorList::LeagueNotFound.into())
+}
+
+pub async fn add_player_to_league(
+    State(state): State<Arc<AppState>>,
+    Json(player): Json<AmendPlayerRequest>,
+) -> Result<StatusCode, AppError> {
+    check_league_open(player.new_league_id, state.clone()).await?;
+    let updated = sqlx::query("UPDATE PLAYERS SET league_id=? WHERE rowid=?")
+        .bind(player.new_league_id)
+        .bind(player.player_id)
+        .execute(&state.db_connection_pool)
+        .await?;
+    if updated.rows_affected() == 0 {
+        return Err(ErrorList::PlayerNotFound.into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Safe to call repeatedly: pairs that already have a fixture this season are
+// skipped, so only newly joined players get fixtures added on later calls
+pub async fn generate_fixtures(
+    State(state): State<Arc<AppState>>,
+    Query(season_query): Query<SeasonQuery>,
+) -> Result<Json<FixtureGenerationSummary>, AppError> {
+    let season_id = resolve_season(season_query.season_id, state.clone()).await?;
+
+    let season = get_season(season_id, state.clone()).await?;
+    let start_date = NaiveDate::parse_from_str(&season.start_date, "%Y-%m-%d")?;
+    let end_date = NaiveDate::parse_from_str(&season.end_date, "%Y-%m-%d")?;
+    // Like joining an archived league, there's no adding fixtures to a season
+    // that's over
+    if season.status == SeasonStatus::Finished || end_date < Utc::now().date_naive() {
+        return Err(ErrorList::SeasonAlreadyFinished.into());
+    }
+
+    let mut transaction = state.db_connection_pool.begin().await?;
+
+    let leagues = sqlx::query("SELECT rowid,league_name FROM leagues WHERE archived=0")
+        .fetch_all(&mut *transaction)
+        .await?;
+
+    let mut summaries = vec![];
+    for league in leagues {
+        let league_id: i64 = league.get(0);
+        let league_players =
+            sqlx::query("SELECT rowid FROM players WHERE league_id=? AND active=1")
+                .bind(league_id)
+                .fetch_all(&mut *transaction)
+                .await?;
+
+        let player_ids: Vec<i64> = league_players.into_iter().map(|x| x.get(0)).collect();
+
+        let existing_fixtures = sqlx::query(
+            "SELECT player_one_id,player_two_id,round,deadline FROM fixtures WHERE season=? AND league_id=?",
+        )
+        .bind(season_id)
+        .bind(league_id)
+        .fetch_all(&mut *transaction)
+        .await?;
+        let mut existing_pairs = HashSet::new();
+        let mut existing_rounds: Vec<scheduling::Round> = vec![];
+        let mut existing_deadlines: Vec<Option<String>> = vec![];
+        for fixture in existing_fixtures {
+            let player_one_id: i64 = fixture.get(0);
+            let player_two_id: i64 = fixture.get(1);
+            existing_pairs.insert((
+                player_one_id.min(player_two_id),
+                player_one_id.max(player_two_id),
+            ));
+            // Fixtures from before rounds existed can't share a round with new ones
+            if let Some(round) = fixture.get::<Option<i64>, _>(2) {
+                let round_index = round as usize - 1;
+                if existing_rounds.len() <= round_index {
+                    existing_rounds.resize(round_index + 1, vec![]);
+                    existing_deadlines.resize(round_index + 1, None);
+                }
+                existing_rounds[round_index].push((player_one_id, player_two_id));
+                existing_deadlines[round_index] = fixture.get(3);
+            }
+        }
+
+        let full_schedule = scheduling::round_robin(&player_ids);
+        let (rounds, deadlines) = if existing_pairs.is_empty() {
+            let deadlines = scheduling::round_deadlines(start_date, end_date, full_schedule.len());
+            (
+                full_schedule,
+                deadlines
+                    .iter()
+                    .map(|deadline| Some(deadline.to_string()))
+                    .collect(),
+            )
+        } else {
+            let missing_pairs: Vec<(i64, i64)> = full_schedule
+                .into_iter()
+                .flatten()
+                .filter(|(a, b)| !existing_pairs.contains(&(*a.min(b), *a.max(b))))
+                .collect();
+            let rounds = scheduling::pack_into_rounds(&missing_pairs, &existing_rounds);
+            // Extra rounds are spread over whatever remains of the season
+            let today = Utc::now().date_naive().clamp(start_date, end_date);
+            let extra_deadlines =
+                scheduling::round_deadlines(today, end_date, rounds.len() - existing_rounds.len());
+            let mut deadlines = existing_deadlines;
+            deadlines.extend(
+                extra_deadlines
+                    .iter()
+                    .map(|deadline| Some(deadline.to_string())),
+            );
+            (rounds, deadlines)
+        };
+
+        let possible_pairs = player_ids.len() * player_ids.len().saturating_sub(1) / 2;
+        let mut fixtures_created = 0;
+        for (round_index, (round, deadline)) in rounds.iter().zip(deadlines).enumerate() {
+            for (player_one_id, player_two_id) in round {
+                sqlx::query("INSERT INTO fixtures (season,league_id,player_one_id,player_two_id,round,deadline) values(?,?,?,?,?,?)")
+                .bind(season_id)
+                .bind(league_id)
+                .bind(player_one_id)
+                .bind(player_two_id)
+                .bind(round_index as i64 + 1)
+                .bind(&deadline)
+                .execute(&mut *transaction)
+                .await?;
+                fixtures_created += 1;
+            }
+        }
+        let rounds_created = rounds.len() - existing_rounds.len();
+
+        summaries.push(LeagueFixtureSummary {
+            league_id,
+            league_name: league.get(1),
+            fixtures_created,
+            fixtures_skipped: possible_pairs - fixtures_created,
+            rounds_created,
+        });
+    }
+
+    transaction.commit().await?;
+
+    Ok(Json(FixtureGenerationSummary {
+        season_id,
+        leagues: summaries,
+    }))
+}
+
+// Results entered by an admin are confirmed straight away
+pub async fn put_result(
+    State(state): State<Arc<AppState>>,
+    Json(match_result): Json<ResultSubmission>,
+) -> Result<StatusCode, AppError> {
+    record_result(&match_result, ResultStatus::Confirmed, None, state).await?;
+    Ok(StatusCode::RESET_CONTENT)
+}
+
+// A player submitting the result of one of their own fixtures, which is held
+// as pending until their opponent confirms it. Matches still in progress are
+// left for an admin, as a confirmed result can't be changed by the players.
+pub async fn submit_own_result(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    Json(match_result): Json<ResultSubmission>,
+) -> Result<StatusCode, AppError> {
+    let player_id = get_linked_player(&user.username, state.clone()).await?;
+    if player_id != match_result.player_one_id && player_id != match_result.player_two_id {
+        return Err(ErrorList::NotYourFixture.into());
+    }
+    if match_result.completed != 1 && match_result.outcome == ResultOutcome::Played {
+        return Err(ErrorList::ResultNotCompleted.into());
+    }
+    record_result(
+        &match_result,
+        ResultStatus::Pending,
+        Some(player_id),
+        state.clone(),
+    )
+    .await?;
+
+    // Let the opponent know there's a result waiting for them
+    let opponent_id = if player_id == match_result.player_one_id {
+        match_result.player_two_id
+    } else {
+        match_result.player_one_id
+    };
+    if let Some(opponent_email) = get_player_email(opponent_id, state.clone()).await? {
+        let player_map = get_player_map(state.clone()).await?;
+        let template = EmailTemplate::ResultConfirmation {
+            opponent: player_map.get(&player_id).cloned().unwrap_or_default(),
+            score: scoring::describe_score(match_result.outcome, &match_result.sets),
+        };
+        queue_email(state, &opponent_email, template).await?;
+    }
+    Ok(StatusCode::RESET_CONTENT)
+}
+
+// Emails both players of every unfinished fixture in the active season whose
+// deadline falls within the next few days
+pub async fn send_fixture_reminders(
+    State(state): State<Arc<AppState>>,
+    Query(reminder_query): Query<ReminderQuery>,
+) -> Result<Json<ReminderSummary>, AppError> {
+    let season_id = get_current_season(state.clone()).await?;
+    let today = Utc::now().date_naive();
+    let until = today + chrono::Duration::days(reminder_query.days.unwrap_or(7));
+
+    let fixtures = sqlx::query_as::<_, (i64, i64, String)>(
+        "SELECT player_one_id,player_two_id,deadline FROM fixtures
+        WHERE season=? and completed=0 and deadline >= ? and deadline <= ?",
+    )
+    .bind(season_id)
+    .bind(today.to_string())
+    .bind(until.to_string())
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+    let player_map = get_player_map(state.clone()).await?;
+
+    let mut summary = ReminderSummary {
+        fixtures: fixtures.len(),
+        reminders_queued: 0,
+    };
+    for (player_one_id, player_two_id, deadline) in fixtures {
+        for (player_id, opponent_id) in [
+            (player_one_id, player_two_id),
+            (player_two_id, player_one_id),
+        ] {
+            let Some(email) = get_player_email(player_id, state.clone()).await? else {
+                continue;
+            };
+            let template = EmailTemplate::FixtureReminder {
+                opponent: player_map.get(&opponent_id).cloned().unwrap_or_default(),
+                deadline: deadline.clone(),
+            };
+            queue_email(state.clone(), &email, template).await?;
+            summary.reminders_queued += 1;
+        }
+    }
+    Ok(Json(summary))
+}
+
+pub async fn confirm_result(
+    State(state): State<Arc<AppState>>,
+    Path(fixture_id): Path<i64>,
+    user: User,
+) -> Result<StatusCode, AppError> {
+    let player_id = get_linked_player(&user.username, state.clone()).await?;
+    let updated = sqlx::query(&format!(
+        "UPDATE fixtures SET result_status='confirmed' WHERE {}",
+        OPPONENT_RESPONSE_CONDITION
+    ))
+    .bind(fixture_id)
+    .bind(player_id)
+    .bind(player_id)
+    .bind(player_id)
+    .execute(&state.db_connection_pool)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(opponent_response_error(fixture_id, player_id, state)
+            .await
+            .into());
+    }
+    Ok(StatusCode::RESET_CONTENT)
+}
+
+pub async fn dispute_result(
+    State(state): State<Arc<AppState>>,
+    Path(fixture_id): Path<i64>,
+    user: User,
+    Json(dispute): Json<DisputeRequest>,
+) -> Result<StatusCode, AppError> {
+    let player_id = get_linked_player(&user.username, state.clone()).await?;
+    let updated = sqlx::query(&format!(
+        "UPDATE fixtures SET result_status='disputed', dispute_reason=? WHERE {}",
+        OPPONENT_RESPONSE_CONDITION
+    ))
+    .bind(dispute.reason)
+    .bind(fixture_id)
+    .bind(player_id)
+    .bind(player_id)
+    .bind(player_id)
+    .execute(&state.db_connection_pool)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(opponent_response_error(fixture_id, player_id, state)
+            .await
+            .into());
+    }
+    Ok(StatusCode::RESET_CONTENT)
+}
+
+pub async fn get_disputed_results(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<DisputedResult>>, AppError> {
+    let fixtures = sqlx::query_as::<_, MatchResult>(&format!(
+        "{} WHERE result_status='disputed' ORDER BY f.rowid",
+        FIXTURE_SELECT
+    ))
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+
+    let mut disputed_results = vec![];
+    for mut fixture in fixtures {
+        fixture.sets = get_fixture_sets(fixture.fixture_id, state.clone()).await?;
+        let dispute_reason: Option<String> =
+            sqlx::query_scalar("SELECT dispute_reason FROM fixtures WHERE rowid=?")
+                .bind(fixture.fixture_id)
+                .fetch_one(&state.db_connection_pool)
+                .await?;
+        disputed_results.push(DisputedResult {
+            fixture,
+            dispute_reason,
+        });
+    }
+    Ok(Json(disputed_results))
+}
+
+// Admins can also correct a disputed result by entering it with PUT /api/result
+pub async fn resolve_disputed_result(
+    State(state): State<Arc<AppState>>,
+    Path(fixture_id): Path<i64>,
+    Json(resolution): Json<DisputeResolutionRequest>,
+) -> Result<StatusCode, AppError> {
+    get_fixture_participants(fixture_id, state.clone()).await?;
+
+    let mut transaction = state.db_connection_pool.begin().await?;
+    let updated = match resolution.resolution {
+        DisputeResolution::Confirm => {
+            sqlx::query(
+                "UPDATE fixtures SET result_status='confirmed', dispute_reason=NULL
+                WHERE rowid=? AND result_status='disputed'",
+            )
+            .bind(fixture_id)
+            .execute(&mut *transaction)
+            .await?
+        }
+        DisputeResolution::Void => {
+            sqlx::query(
+                "UPDATE fixtures SET
+                outcome=NULL,
+                completed=0,
+                winner=NULL,
+                result_status=NULL,
+                submitted_by=NULL,
+                dispute_reason=NULL
+                WHERE rowid=? AND result_status='disputed'",
+            )
+            .bind(fixture_id)
+            .execute(&mut *transaction)
+            .await?
+        }
+    };
+    if updated.rows_affected() == 0 {
+        return Err(ErrorList::ResultNotDisputed.into());
+    }
+    if resolution.resolution == DisputeResolution::Void {
+        sqlx::query("DELETE FROM fixture_sets WHERE fixture_id=?")
+            .bind(fixture_id)
+            .execute(&mut *transaction)
+            .await?;
+    }
+    transaction.commit().await?;
+    Ok(StatusCode::RESET_CONTENT)
+}
+
+pub async fn link_player_to_user(
+    State(state): State<Arc<AppState>>,
+    Path(player_id): Path<i64>,
+    Json(link): Json<LinkPlayerRequest>,
+) -> Result<StatusCode, AppError> {
+    let user_exists: Option<i64> = sqlx::query_scalar("SELECT 1 FROM users WHERE username=?")
+        .bind(&link.username)
+        .fetch_optional(&state.db_connection_pool)
+        .await?;
+    if user_exists.is_none() {
+        return Err(ErrorList::UserNotFound.into());
+    }
+
+    let mut transaction = state.db_connection_pool.begin().await?;
+    link_player(player_id, &link.username, &mut transaction).await?;
+    transaction.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Invite codes last a week and can only be used once. Like other codes only
+// a hash is stored, so the code is only ever seen in this response.
+pub async fn create_player_invite(
+    State(state): State<Arc<AppState>>,
+    Path(player_id): Path<i64>,
+) -> Result<(StatusCode, Json<PlayerInvite>), AppError> {
+    let player_exists: Option<i64> = sqlx::query_scalar("SELECT 1 FROM players WHERE rowid=?")
+        .bind(player_id)
+        .fetch_optional(&state.db_connection_pool)
+        .await?;
+    if player_exists.is_none() {
+        return Err(ErrorList::PlayerNotFound.into());
+    }
+
+    let invite = PlayerInvite {
+        code: generate_unique_id(12),
+        player_id,
+        expiry_ts: Utc::now().timestamp() + 7 * 24 * 3600,
+    };
+    sqlx::query("INSERT INTO player_invites(code,player_id,created_ts,expiry_ts) values(?,?,?,?)")
+        .bind(hash_token(&invite.code))
+        .bind(invite.player_id)
+        .bind(Utc::now().timestamp())
+        .bind(invite.expiry_ts)
+        .execute(&state.db_connection_pool)
+        .await?;
+    Ok((StatusCode::CREATED, Json(invite)))
+}
+
+pub async fn claim_player_invite(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    Json(claim): Json<ClaimInviteRequest>,
+) -> Result<StatusCode, AppError> {
+    let code_hash = hash_token(&claim.code.trim().to_ascii_uppercase());
+    let mut transaction = state.db_connection_pool.begin().await?;
+    let player_id: Option<i64> = sqlx::query_scalar(
+        "SELECT player_id FROM player_invites WHERE code=? AND used=0 AND expiry_ts > ?",
+    )
+    .bind(&code_hash)
+    .bind(Utc::now().timestamp())
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let Some(player_id) = player_id else {
+        return Err(ErrorList::InvalidInviteCode.into());
+    };
+
+    let claimed = sqlx::query("UPDATE player_invites SET used=1 WHERE code=? AND used=0")
+        .bind(&code_hash)
+        .execute(&mut *transaction)
+        .await?;
+    if claimed.rows_affected() == 0 {
+        return Err(ErrorList::InvalidInviteCode.into());
+    }
+    link_player(player_id, &user.username, &mut transaction).await?;
+    transaction.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn request_player_link(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    Json(request): Json<LinkRequest>,
+) -> Result<StatusCode, AppError> {
+    let player_username =
+        sqlx::query_as::<_, (Option<String>,)>("SELECT username FROM players WHERE rowid=?")
+            .bind(request.player_id)
+            .fetch_optional(&state.db_connection_pool)
+            .await?;
+    match player_username {
+        None => return Err(ErrorList::PlayerNotFound.into()),
+        Some((Some(_),)) => return Err(ErrorList::PlayerAlreadyLinked.into()),
+        Some((None,)) => {}
+    }
+    if get_linked_player(&user.username, state.clone())
+        .await
+        .is_ok()
+    {
+        return Err(ErrorList::AccountAlreadyLinked.into());
+    }
+
+    sqlx::query("INSERT INTO player_link_requests(player_id,username,created_ts) values(?,?,?)")
+        .bind(request.player_id)
+        .bind(&user.username)
+        .bind(Utc::now().timestamp())
+        .execute(&state.db_connection_pool)
+        .await?;
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn get_link_requests(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<PlayerLinkRequest>>, AppError> {
+    let requests = sqlx::query_as::<_, PlayerLinkRequest>(
+        "SELECT r.rowid,player_id,p.name as 'player_name',r.username,created_ts
+        FROM player_link_requests r
+        join players p on p.rowid = player_id
+        WHERE status='pending'
+        ORDER BY created_ts",
+    )
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+    Ok(Json(requests))
+}
+
+pub async fn decide_link_request(
+    State(state): State<Arc<AppState>>,
+    Path(request_id): Path<i64>,
+    Json(decision): Json<LinkRequestDecision>,
+) -> Result<StatusCode, AppError> {
+    let mut transaction = state.db_connection_pool.begin().await?;
+    let request = sqlx::query_as::<_, (i64, String)>(
+        "SELECT player_id,username FROM player_link_requests WHERE rowid=? AND status='pending'",
+    )
+    .bind(request_id)
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let Some((player_id, username)) = request else {
+        return Err(ErrorList::LinkRequestNotFound.into());
+    };
+
+    let status = if decision.approve {
+        link_player(player_id, &username, &mut transaction).await?;
+        "approved"
+    } else {
+        "rejected"
+    };
+    sqlx::query("UPDATE player_link_requests SET status=? WHERE rowid=?")
+        .bind(status)
+        .bind(request_id)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_me(State(state): State<Arc<AppState>>, user: User) -> Result<Json<Me>, AppError> {
+    let player = sqlx::query_as::<_, LinkedPlayer>(
+        "SELECT p.rowid,name,league_id,l.league_name
+        FROM players p
+        left join leagues l on l.rowid = league_id
+        WHERE username=?",
+    )
+    .bind(&user.username)
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+    let season_id = get_current_season(state.clone()).await.ok();
+
+    let (upcoming_fixtures, completed_fixtures) = match (&player, season_id) {
+        (Some(player), Some(season_id)) => (
+            get_player_fixtures(player.player_id, season_id, false, state.clone()).await?,
+            get_player_fixtures(player.player_id, season_id, true, state.clone()).await?,
+        ),
+        _ => (vec![], vec![]),
+    };
+
+    Ok(Json(Me {
+        username: user.username,
+        email: user.email,
+        player,
+        season_id,
+        upcoming_fixtures,
+        completed_fixtures,
+    }))
+}
+
+pub async fn generate_league_table(
+    Path(league_id): Path<i64>,
+    Query(season_query): Query<SeasonQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<LeagueTableAndFixtures>, AppError> {
+    let season_id = resolve_season(season_query.season_id, state.clone()).await?;
+    let match_format = get_league_format(league_id, state.clone()).await?;
+    let points_scheme = get_points_scheme(league_id, state.clone()).await?;
+    let player_map = get_player_map(state.clone()).await?;
+
+    let uncompleted_fixtures = get_fixtures(league_id, season_id, false, state.clone()).await?;
+
+    let completed_fixtures = get_fixtures(league_id, season_id, true, state.clone()).await?;
+
+    let rounds = summarise_rounds(&completed_fixtures, &uncompleted_fixtures);
+
+    let league_players = get_league_players(league_id, season_id, state).await?;
+
+    let league_table = compute_league_table(
+        league_players,
+        player_map,
+        &completed_fixtures,
+        match_format,
+        &points_scheme,
+    );
+
+    let league_table_and_fixtures = LeagueTableAndFixtures {
+        match_format,
+        max_sets: match_format.rules().max_sets(),
+        points_scheme,
+        completed_fixtures,
+        uncompleted_fixtures,
+        league_table,
+        rounds,
+    };
+
+    Ok(Json(league_table_and_fixtures))
+}
+
+pub async fn get_leagues(
+    State(state): State<Arc<AppState>>,
+    Query(leagues_query): Query<LeaguesQuery>,
+) -> Result<Json<Vec<League>>, AppError> {
+    let leagues: Vec<League> = sqlx::query_as::<_, League>(
+        "SELECT rowid,league_name,league_tier,match_format,archived FROM leagues
+        WHERE archived=0 OR ? ORDER BY league_tier",
+    )
+    .bind(leagues_query.include_archived)
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+    Ok(Json(leagues))
+}
+
+pub async fn get_seasons(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<Season>>, AppError> {
+    let seasons = sqlx::query_as::<_, Season>(
+        "SELECT rowid,season_name,start_date,end_date,status FROM seasons ORDER BY start_date",
+    )
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+    Ok(Json(seasons))
+}
+
+pub async fn create_season(
+    State(state): State<Arc<AppState>>,
+    Json(season): Json<NewSeasonRequest>,
+) -> Result<(StatusCode, Json<Season>), AppError> {
+    let start_date = NaiveDate::parse_from_str(&season.start_date, "%Y-%m-%d")
+        .map_err(|_| ErrorList::InvalidSeasonDates)?;
+    let end_date = NaiveDate::parse_from_str(&season.end_date, "%Y-%m-%d")
+        .map_err(|_| ErrorList::InvalidSeasonDates)?;
+    if end_date <= start_date {
+        return Err(ErrorList::InvalidSeasonDates.into());
+    }
+
+    let created = sqlx::query_as::<_, Season>(
+        "INSERT INTO seasons(season_name,start_date,end_date,status) values(?,?,?,'planned')
+        RETURNING rowid,season_name,start_date,end_date,status",
+    )
+    .bind(season.season_name)
+    .bind(start_date.to_string())
+    .bind(end_date.to_string())
+    .fetch_one(&state.db_connection_pool)
+    .await?;
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+pub async fn activate_season(
+    State(state): State<Arc<AppState>>,
+    Json(season): Json<ActivateSeasonRequest>,
+) -> Result<StatusCode, AppError> {
+    let mut transaction = state.db_connection_pool.begin().await?;
+
+    let status = sqlx::query_as::<_, (SeasonStatus,)>("SELECT status FROM seasons WHERE rowid=?")
+        .bind(season.season_id)
+        .fetch_optional(&mut *transaction)
+        .await?;
+    match status {
+        None => return Err(ErrorList::SeasonNotFound.into()),
+        Some((SeasonStatus::Finished,)) => return Err(ErrorList::SeasonAlreadyFinished.into()),
+        Some(_) => {}
+    }
+
+    // Only one season can be active, so the previous one is finished
+    sqlx::query("UPDATE seasons SET status='finished' WHERE status='active' AND rowid!=?")
+        .bind(season.season_id)
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query("UPDATE seasons SET status='active' WHERE rowid=?")
+        .bind(season.season_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    transaction.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn set_league_format(
+    Path(league_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Json(format): Json<LeagueFormatRequest>,
+) -> Result<StatusCode, AppError> {
+    let updated = sqlx::query("UPDATE leagues SET match_format=? WHERE rowid=?")
+        .bind(format.match_format)
         .bind(league_id)
+        .execute(&state.db_connection_pool)
+        .await?;
+    if updated.rows_affected() == 0 {
+        return Err(ErrorList::LeagueNotFound.into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn set_league_scoring(
+    Path(league_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Json(points_scheme): Json<PointsScheme>,
+) -> Result<StatusCode, AppError> {
+    let updated = sqlx::query(
+        "UPDATE leagues SET
+        points_per_win=?,
+        points_per_loss=?,
+        points_per_set=?,
+        points_per_walkover=?,
+        points_per_retirement=?,
+        points_per_walkover_loss=?,
+        points_per_double_walkover=?,
+        tie_breakers=?
+        WHERE rowid=?",
+    )
+    .bind(points_scheme.points_per_win)
+    .bind(points_scheme.points_per_loss)
+    .bind(points_scheme.points_per_set)
+    .bind(points_scheme.points_per_walkover)
+    .bind(points_scheme.points_per_retirement)
+    .bind(points_scheme.points_per_walkover_loss)
+    .bind(points_scheme.points_per_double_walkover)
+    .bind(points_scheme.tie_breakers_string())
+    .bind(league_id)
+    .execute(&state.db_connection_pool)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(ErrorList::LeagueNotFound.into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn set_league_movement(
+    Path(league_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Json(movement): Json<LeagueMovementRequest>,
+) -> Result<StatusCode, AppError> {
+    if movement.promotion_places < 0 || movement.relegation_places < 0 {
+        return Err(ErrorList::InvalidMovementPlaces.into());
+    }
+    let updated =
+        sqlx::query("UPDATE leagues SET promotion_places=?, relegation_places=? WHERE rowid=?")
+            .bind(movement.promotion_places)
+            .bind(movement.relegation_places)
+            .bind(league_id)
+            .execute(&state.db_connection_pool)
+            .await?;
+    if updated.rows_affected() == 0 {
+        return Err(ErrorList::LeagueNotFound.into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Dry run showing who would move between tiers based on the final standings
+pub async fn preview_promotion_relegation(
+    Path(season_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<PromotionRelegation>, AppError> {
+    let season_id = resolve_season(Some(season_id), state.clone()).await?;
+    let movements = compute_movements(season_id, state).await?;
+    Ok(Json(PromotionRelegation {
+        season_id,
+        applied: false,
+        movements,
+    }))
+}
+
+pub async fn apply_promotion_relegation(
+    Path(season_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<PromotionRelegation>, AppError> {
+    let season_id = resolve_season(Some(season_id), state.clone()).await?;
+    // Standings can still change until the season is over
+    if get_season(season_id, state.clone()).await?.status != SeasonStatus::Finished {
+        return Err(ErrorList::SeasonNotFinished.into());
+    }
+    let movements = compute_movements(season_id, state.clone()).await?;
+
+    let mut transaction = state.db_connection_pool.begin().await?;
+
+    // Guard against moving players twice for the same season
+    let marked =
+        sqlx::query("UPDATE seasons SET movements_applied=1 WHERE rowid=? AND movements_applied=0")
+            .bind(season_id)
+            .execute(&mut *transaction)
+            .await?;
+    if marked.rows_affected() == 0 {
+        return Err(ErrorList::MovementsAlreadyApplied.into());
+    }
+
+    for movement in movements.iter() {
+        sqlx::query("UPDATE players SET league_id=? WHERE rowid=?")
+            .bind(movement.to_league_id)
+            .bind(movement.player_id)
+            .execute(&mut *transaction)
+            .await?;
+    }
+
+    transaction.commit().await?;
+    Ok(Json(PromotionRelegation {
+        season_id,
+        applied: true,
+        movements,
+    }))
+}
+
+// Functions below this point are not routes and should be moved elsewhere
+
+async fn compute_movements(
+    season_id: i64,
+    state: Arc<AppState>,
+) -> Result<Vec<PlayerMovement>, anyhow::Error> {
+    // Tier 1 is the top tier, so promotion moves towards the start of this list
+    let leagues = sqlx::query_as::<_, LeagueMovementPlaces>(
+        "SELECT rowid,promotion_places,relegation_places,match_format FROM leagues
+        WHERE archived=0 ORDER BY league_tier",
+    )
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+    let player_map = get_player_map(state.clone()).await?;
+
+    let mut standings = vec![];
+    for league in leagues {
+        let completed_fixtures =
+            get_fixtures(league.league_id, season_id, true, state.clone()).await?;
+        let league_players = get_league_players(league.league_id, season_id, state.clone()).await?;
+        let points_scheme = get_points_scheme(league.league_id, state.clone()).await?;
+        standings.push(LeagueStandings {
+            league_id: league.league_id,
+            promotion_places: league.promotion_places,
+            relegation_places: league.relegation_places,
+            table: compute_league_table(
+                league_players,
+                player_map.clone(),
+                &completed_fixtures,
+                league.match_format,
+                &points_scheme,
+            ),
+        });
+    }
+    Ok(plan_movements(&standings))
+}
+
+async fn record_result(
+    match_result: &ResultSubmission,
+    result_status: ResultStatus,
+    submitted_by: Option<i64>,
+    state: Arc<AppState>,
+) -> Result<(), anyhow::Error> {
+    let season_id = resolve_season(match_result.season, state.clone()).await?;
+    let rules = get_league_format(match_result.league_id, state.clone())
+        .await?
+        .rules();
+
+    let declared_winner = match match_result.winner {
+        None => None,
+        Some(winner) if winner == match_result.player_one_id => Some(Side::PlayerOne),
+        Some(winner) if winner == match_result.player_two_id => Some(Side::PlayerTwo),
+        Some(_) => return Err(ErrorList::WinnerNotInFixture.into()),
+    };
+    // Only a played match can be left in progress
+    let completed = match_result.completed == 1 || match_result.outcome != ResultOutcome::Played;
+    let winner = match scoring::resolve_result(
+        &rules,
+        match_result.outcome,
+        &match_result.sets,
+        declared_winner,
+        completed,
+    )? {
+        Some(Side::PlayerOne) => Some(match_result.player_one_id),
+        Some(Side::PlayerTwo) => Some(match_result.player_two_id),
+        None => None,
+    };
+
+    let mut transaction = state.db_connection_pool.begin().await?;
+
+    let fixture = sqlx::query_as::<_, (i64, Option<ResultStatus>)>(
+        "SELECT rowid,result_status FROM fixtures
+        WHERE
+        season=? and
+        league_id=? and
+        player_one_id=? and
+        player_two_id=?",
+    )
+    .bind(season_id)
+    .bind(match_result.league_id)
+    .bind(match_result.player_one_id)
+    .bind(match_result.player_two_id)
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let fixture_id = match fixture {
+        // Players can only submit a result once, after that it's up to the
+        // opponent to respond or an admin to change it
+        Some((_, Some(ResultStatus::Confirmed))) if submitted_by.is_some() => {
+            return Err(ErrorList::ResultAlreadyConfirmed.into())
+        }
+        Some((_, Some(_))) if submitted_by.is_some() => {
+            return Err(ErrorList::ResultAlreadySubmitted.into())
+        }
+        Some((fixture_id, _)) => fixture_id,
+        None => return Err(ErrorList::FixtureNotFound.into()),
+    };
+
+    // The status is checked again here in case another submission got in first
+    let updated = sqlx::query(
+        "UPDATE FIXTURES SET
+        outcome=?,
+        completed=?,
+        winner=?,
+        result_status=?,
+        submitted_by=?,
+        dispute_reason=NULL
+        WHERE rowid=? AND (? OR result_status IS NULL)",
+    )
+    .bind(match_result.outcome)
+    .bind(completed)
+    .bind(winner)
+    .bind(result_status)
+    .bind(submitted_by)
+    .bind(fixture_id)
+    .bind(submitted_by.is_none())
+    .execute(&mut *transaction)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(ErrorList::ResultAlreadySubmitted.into());
+    }
+
+    sqlx::query("DELETE FROM fixture_sets WHERE fixture_id=?")
+        .bind(fixture_id)
+        .execute(&mut *transaction)
+        .await?;
+    for (set_index, set) in match_result.sets.iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO fixture_sets(fixture_id,set_number,player_one_games,player_two_games) values(?,?,?,?)",
+        )
+        .bind(fixture_id)
+        .bind(set_index as i64 + 1)
+        .bind(set.player_one)
+        .bind(set.player_two)
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    transaction.commit().await?;
+    Ok(())
+}
+
+// Only the opponent of whoever submitted a pending result can respond to it.
+// Checked in the update itself so two responses can't both go through.
+const OPPONENT_RESPONSE_CONDITION: &str = "rowid=? AND result_status='pending'
+    AND (submitted_by IS NULL OR submitted_by != ?)
+    AND (player_one_id=? OR player_two_id=?)";
+
+// Works out why a confirm or dispute didn't change the result
+async fn opponent_response_error(
+    fixture_id: i64,
+    player_id: i64,
+    state: Arc<AppState>,
+) -> anyhow::Error {
+    match check_opponent_response(fixture_id, player_id, state).await {
+        Err(e) => e,
+        Ok(()) => ErrorList::ResultNotPending.into(),
+    }
+}
+
+async fn check_opponent_response(
+    fixture_id: i64,
+    player_id: i64,
+    state: Arc<AppState>,
+) -> Result<(), anyhow::Error> {
+    let fixture = get_fixture_participants(fixture_id, state).await?;
+    if player_id != fixture.player_one_id && player_id != fixture.player_two_id {
+        return Err(ErrorList::NotYourFixture.into());
+    }
+    if fixture.result_status != Some(ResultStatus::Pending) {
+        return Err(ErrorList::ResultNotPending.into());
+    }
+    if fixture.submitted_by == Some(player_id) {
+        return Err(ErrorList::OwnResultSubmission.into());
+    }
+    Ok(())
+}
+
+async fn get_fixture_participants(
+    fixture_id: i64,
+    state: Arc<AppState>,
+) -> Result<FixtureParticipants, anyhow::Error> {
+    let fixture = sqlx::query_as::<_, FixtureParticipants>(
+        "SELECT player_one_id,player_two_id,result_status,submitted_by FROM fixtures WHERE rowid=?",
+    )
+    .bind(fixture_id)
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+    fixture.ok_or(ErrorList::FixtureNotFound.into())
+}
+
+// Links the player to the account, as long as neither is linked already
+async fn link_player(
+    player_id: i64,
+    username: &str,
+    transaction: &mut Transaction<'_, Sqlite>,
+) -> Result<(), anyhow::Error> {
+    let account_linked: Option<i64> =
+        sqlx::query_scalar("SELECT rowid FROM players WHERE username=?")
+            .bind(username)
+            .fetch_optional(&mut **transaction)
+            .await?;
+    if account_linked.is_some() {
+        return Err(ErrorList::AccountAlreadyLinked.into());
+    }
+
+    let player_username =
+        sqlx::query_as::<_, (Option<String>,)>("SELECT username FROM players WHERE rowid=?")
+            .bind(player_id)
+            .fetch_optional(&mut **transaction)
+            .await?;
+    match player_username {
+        None => return Err(ErrorList::PlayerNotFound.into()),
+        Some((Some(_),)) => return Err(ErrorList::PlayerAlreadyLinked.into()),
+        Some((None,)) => {}
+    }
+
+    sqlx::query("UPDATE players SET username=? WHERE rowid=?")
+        .bind(username)
+        .bind(player_id)
+        .execute(&mut **transaction)
+        .await?;
+    Ok(())
+}
+
+// The email of the account linked to the player, if there is one
+async fn get_player_email(
+    player_id: i64,
+    state: Arc<AppState>,
+) -> Result<Option<String>, anyhow::Error> {
+    let email: Option<String> = sqlx::query_scalar(
+        "SELECT u.email FROM players p join users u on u.username = p.username WHERE p.rowid=?",
+    )
+    .bind(player_id)
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+    Ok(email)
+}
+
+async fn get_linked_player(username: &str, state: Arc<AppState>) -> Result<i64, anyhow::Error> {
+    let player_id: Option<i64> = sqlx::query_scalar("SELECT rowid FROM players WHERE username=?")
+        .bind(username)
+        .fetch_optional(&state.db_connection_pool)
+        .await?;
+    player_id.ok_or(ErrorList::NotLinkedToPlayer.into())
+}
+
+async fn get_current_season(state: Arc<AppState>) -> Result<i64, anyhow::Error> {
+    let season = sqlx::query("SELECT rowid FROM seasons WHERE status='active'")
+        .fetch_optional(&state.db_connection_pool)
+        .await?;
+    match season {
+        Some(season) => Ok(season.get(0)),
+        None => Err(ErrorList::NoActiveSeason.into()),
+    }
+}
+
+// Use the requested season if it exists, otherwise fall back to the active one
+async fn resolve_season(
+    season_id: Option<i64>,
+    state: Arc<AppState>,
+) -> Result<i64, anyhow::Error> {
+    let Some(season_id) = season_id else {
+        return get_current_season(state).await;
+    };
+    let season = sqlx::query("SELECT rowid FROM seasons WHERE rowid=?")
+        .bind(season_id)
+        .fetch_optional(&state.db_connection_pool)
+        .await?;
+    match season {
+        Some(_) => Ok(season_id),
+        None => Err(ErrorList::SeasonNotFound.into()),
+    }
+}
+
+async fn get_player_map(state: Arc<AppState>) -> Result<HashMap<i64, String>, anyhow::Error> {
+    let players = sqlx::query("SELECT rowid,name FROM players")
         .fetch_all(&state.db_connection_pool)
         .await?;
+    let mut players_map: HashMap<i64, String> = HashMap::new();
+
+    for player in players {
+        players_map.insert(player.get(0), player.get(1));
+    }
+    Ok(players_map)
+}
+
+// Everyone who has fixtures in the league that season, plus current members
+// while the season is still active
+async fn get_league_players(
+    league_id: i64,
+    season_id: i64,
+    state: Arc<AppState>,
+) -> Result<Vec<i64>, anyhow::Error> {
+    let league_players = sqlx::query(
+        "SELECT player_one_id FROM fixtures WHERE league_id=? AND season=?
+        UNION
+        SELECT player_two_id FROM fixtures WHERE league_id=? AND season=?
+        UNION
+        SELECT p.rowid FROM players p JOIN seasons s ON s.rowid=? AND s.status='active'
+        WHERE p.league_id=?",
+    )
+    .bind(league_id)
+    .bind(season_id)
+    .bind(league_id)
+    .bind(season_id)
+    .bind(season_id)
+    .bind(league_id)
+    .fetch_all(&state.db_connection_pool)
+    .await?;
 
     Ok(league_players.into_iter().map(|x| x.get(0)).collect())
 }
+
+const FIXTURE_SELECT: &str = "SELECT
+    f.rowid,
+    season,
+    f.league_id,
+    player_one_id,
+    player_two_id,
+    round,
+    deadline,
+    outcome,
+    completed,
+    winner,
+    result_status,
+    submitted_by,
+    p1.name as 'player_one_name',
+    p2.name as 'player_two_name'
+    FROM fixtures f
+    join players p1 on p1.rowid = player_one_id
+    join players p2 on p2.rowid = player_two_id";
+
+async fn get_fixtures(
+    league_id: i64,
+    season_id: i64,
+    completed: bool,
+    state: Arc<AppState>,
+) -> Result<Vec<MatchResult>, anyhow::Error> {
+    let mut fixtures = sqlx::query_as::<_, MatchResult>(&format!(
+        "{} WHERE f.league_id=? and season=? and completed=? ORDER BY round, f.rowid",
+        FIXTURE_SELECT
+    ))
+    .bind(league_id)
+    .bind(season_id)
+    .bind(completed)
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+
+    let fixture_sets = sqlx::query_as::<_, FixtureSet>(
+        "SELECT fixture_id,player_one_games,player_two_games
+        FROM fixture_sets fs
+        join fixtures f on f.rowid = fs.fixture_id
+        WHERE f.league_id=? and season=? and completed=?
+        ORDER BY fixture_id, set_number",
+    )
+    .bind(league_id)
+    .bind(season_id)
+    .bind(completed)
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+
+    let mut sets_by_fixture: HashMap<i64, Vec<SetScore>> = HashMap::new();
+    for set in fixture_sets {
+        sets_by_fixture
+            .entry(set.fixture_id)
+            .or_default()
+            .push(SetScore {
+                player_one: set.player_one_games,
+                player_two: set.player_two_games,
+            });
+    }
+    for fixture in fixtures.iter_mut() {
+        fixture.sets = sets_by_fixture
+            .remove(&fixture.fixture_id)
+            .unwrap_or_default();
+    }
+    Ok(fixtures)
+}
+
+async fn get_player_fixtures(
+    player_id: i64,
+    season_id: i64,
+    completed: bool,
+    state: Arc<AppState>,
+) -> Result<Vec<MatchResult>, anyhow::Error> {
+    let mut fixtures = sqlx::query_as::<_, MatchResult>(&format!(
+        "{} WHERE (player_one_id=? or player_two_id=?) and season=? and completed=?
+        ORDER BY round, f.rowid",
+        FIXTURE_SELECT
+    ))
+    .bind(player_id)
+    .bind(player_id)
+    .bind(season_id)
+    .bind(completed)
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+    for fixture in fixtures.iter_mut() {
+        fixture.sets = get_fixture_sets(fixture.fixture_id, state.clone()).await?;
+    }
+    Ok(fixtures)
+}
+
+async fn get_fixture_sets(
+    fixture_id: i64,
+    state: Arc<AppState>,
+) -> Result<Vec<SetScore>, anyhow::Error> {
+    let sets = sqlx::query_as::<_, SetScore>(
+        "SELECT player_one_games as player_one, player_two_games as player_two
+        FROM fixture_sets WHERE fixture_id=? ORDER BY set_number",
+    )
+    .bind(fixture_id)
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+    Ok(sets)
+}
+
+async fn get_points_scheme(
+    league_id: i64,
+    state: Arc<AppState>,
+) -> Result<PointsScheme, anyhow::Error> {
+    let points_scheme = sqlx::query_as::<_, PointsSchemeRow>(
+        "SELECT
+        points_per_win,
+        points_per_loss,
+        points_per_set,
+        points_per_walkover,
+        points_per_retirement,
+        points_per_walkover_loss,
+        points_per_double_walkover,
+        tie_breakers
+        FROM leagues WHERE rowid=?",
+    )
+    .bind(league_id)
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+    match points_scheme {
+        Some(points_scheme) => points_scheme.try_into(),
+        None => Err(ErrorList::LeagueNotFound.into()),
+    }
+}
+
+async fn get_league_format(
+    league_id: i64,
+    state: Arc<AppState>,
+) -> Result<MatchFormat, anyhow::Error> {
+    let format =
+        sqlx::query_as::<_, (MatchFormat,)>("SELECT match_format FROM leagues WHERE rowid=?")
+            .bind(league_id)
+            .fetch_optional(&state.db_connection_pool)
+            .await?;
+    match format {
+        Some((format,)) => Ok(format),
+        None => Err(ErrorList::LeagueNotFound.into()),
+    }
+}
+
+async fn get_season(season_id: i64, state: Arc<AppState>) -> Result<Season, anyhow::Error> {
+    let season = sqlx::query_as::<_, Season>(
+        "SELECT rowid,season_name,start_date,end_date,status FROM seasons WHERE rowid=?",
+    )
+    .bind(season_id)
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+    season.ok_or(ErrorList::SeasonNotFound.into())
+}
+
+// Fixtures generated before rounds existed have no round and are left out
+fn summarise_rounds(completed: &[MatchResult], uncompleted: &[MatchResult]) -> Vec<FixtureRound> {
+    let mut rounds: BTreeMap<i64, FixtureRound> = BTreeMap::new();
+    for fixture in completed.iter().chain(uncompleted) {
+        let Some(round) = fixture.round else {
+            continue;
+        };
+        let summary = rounds.entry(round).or_insert(FixtureRound {
+            round,
+            deadline: fixture.deadline.clone(),
+            fixtures: 0,
+            completed_fixtures: 0,
+        });
+        summary.fixtures += 1;
+        if fixture.completed == 1 {
+            summary.completed_fixtures += 1;
+        }
+    }
+    rounds.into_values().collect()
+}
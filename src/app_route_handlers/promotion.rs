@@ -0,0 +1,70 @@
+use super::standings::LeagueTableRow;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MovementDirection {
+    Promotion,
+    Relegation,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlayerMovement {
+    pub player_id: i64,
+    pub player_name: String,
+    pub final_position: usize,
+    pub from_league_id: i64,
+    pub to_league_id: i64,
+    pub direction: MovementDirection,
+}
+
+// A league's final table along with how many places go up and down
+pub struct LeagueStandings {
+    pub league_id: i64,
+    pub promotion_places: i64,
+    pub relegation_places: i64,
+    pub table: Vec<LeagueTableRow>,
+}
+
+// Works out who moves between tiers. Leagues must be ordered from the top tier
+// down, and nobody is promoted from the top tier or relegated from the bottom.
+pub fn plan_movements(leagues: &[LeagueStandings]) -> Vec<PlayerMovement> {
+    let mut movements = vec![];
+    for (index, league) in leagues.iter().enumerate() {
+        let table = &league.table;
+        let promotion_places = match index {
+            0 => 0,
+            _ => (league.promotion_places.max(0) as usize).min(table.len()),
+        };
+        let relegation_places = match leagues.get(index + 1) {
+            // A player can't be promoted and relegated in the same season
+            Some(_) => {
+                (league.relegation_places.max(0) as usize).min(table.len() - promotion_places)
+            }
+            None => 0,
+        };
+
+        for (position, row) in table.iter().enumerate().take(promotion_places) {
+            movements.push(PlayerMovement {
+                player_id: row.player_id,
+                player_name: row.name.clone(),
+                final_position: position + 1,
+                from_league_id: league.league_id,
+                to_league_id: leagues[index - 1].league_id,
+                direction: MovementDirection::Promotion,
+            });
+        }
+        let first_relegated = table.len() - relegation_places;
+        for (position, row) in table.iter().enumerate().skip(first_relegated) {
+            movements.push(PlayerMovement {
+                player_id: row.player_id,
+                player_name: row.name.clone(),
+                final_position: position + 1,
+                from_league_id: league.league_id,
+                to_league_id: leagues[index + 1].league_id,
+                direction: MovementDirection::Relegation,
+            });
+        }
+    }
+    movements
+}
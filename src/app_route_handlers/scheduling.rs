@@ -0,0 +1,79 @@
+use chrono::{Duration, NaiveDate};
+use std::collections::HashSet;
+
+// A round is a list of (player one, player two) pairs where nobody appears twice
+pub type Round = Vec<(i64, i64)>;
+
+// Circle method round-robin. One player stays fixed while the rest rotate
+// around them, so every pair meets exactly once across n - 1 rounds. With an
+// odd number of players a bye is added and whoever draws it sits the round out.
+pub fn round_robin(player_ids: &[i64]) -> Vec<Round> {
+    let mut slots: Vec<Option<i64>> = player_ids.iter().copied().map(Some).collect();
+    if slots.len() % 2 == 1 {
+        slots.push(None);
+    }
+    let slot_count = slots.len();
+    if slot_count < 2 {
+        return vec![];
+    }
+
+    let mut rounds = vec![];
+    for round_number in 0..slot_count - 1 {
+        let mut round = vec![];
+        for i in 0..slot_count / 2 {
+            if let (Some(home), Some(away)) = (slots[i], slots[slot_count - 1 - i]) {
+                // Alternate the fixed player's side so they aren't always player one
+                if i == 0 && round_number % 2 == 1 {
+                    round.push((away, home));
+                } else {
+                    round.push((home, away));
+                }
+            }
+        }
+        rounds.push(round);
+        // Keep the first slot fixed and rotate everything else clockwise
+        slots[1..].rotate_right(1);
+    }
+    rounds
+}
+
+// Spread round deadlines evenly between the season start and end dates
+pub fn round_deadlines(
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+    rounds: usize,
+) -> Vec<NaiveDate> {
+    let season_days = (end_date - start_date).num_days();
+    let rounds = rounds as i64;
+    (1..=rounds)
+        .map(|round| start_date + Duration::days(season_days * round / rounds))
+        .collect()
+}
+
+// Greedily places each pair in the earliest round where neither player is
+// already playing, opening new rounds as needed. Used when players join part
+// way through a season so they can fill any byes before extra rounds are added.
+pub fn pack_into_rounds(pairs: &[(i64, i64)], existing_rounds: &[Round]) -> Vec<Round> {
+    let mut occupied: Vec<HashSet<i64>> = existing_rounds
+        .iter()
+        .map(|round| round.iter().flat_map(|&(a, b)| [a, b]).collect())
+        .collect();
+    let mut additions: Vec<Round> = vec![vec![]; existing_rounds.len()];
+
+    for &(player_one_id, player_two_id) in pairs {
+        let free_round = occupied
+            .iter()
+            .position(|round| !round.contains(&player_one_id) && !round.contains(&player_two_id));
+        let round_index = match free_round {
+            Some(round_index) => round_index,
+            None => {
+                occupied.push(HashSet::new());
+                additions.push(vec![]);
+                occupied.len() - 1
+            }
+        };
+        occupied[round_index].extend([player_one_id, player_two_id]);
+        additions[round_index].push((player_one_id, player_two_id));
+    }
+    additions
+}
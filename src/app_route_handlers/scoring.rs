@@ -0,0 +1,375 @@
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use thiserror::Error;
+
+// Formats a league can be played in, stored against each league
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum MatchFormat {
+    // Two sets with a match tiebreak to 10 instead of a third set
+    #[default]
+    TwoSetsMatchTiebreak,
+    BestOfThreeSets,
+    // Best of three short sets to 4 with a tiebreak at 3-3 and no-ad games
+    Fast4,
+    // A single set to 8 with a tiebreak at 8-8
+    ProSet,
+    // Two sets plus a match tiebreak with no-ad games
+    NoAd,
+}
+
+// How a fixture was resolved
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum ResultOutcome {
+    #[default]
+    Played,
+    // Partial score kept, the player who didn't retire is the winner
+    Retired,
+    Walkover,
+    DoubleWalkover,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct FormatRules {
+    pub sets_to_win: usize,
+    pub games_per_set: i64,
+    pub tiebreak_at: i64,
+    pub deciding_match_tiebreak: bool,
+    pub no_ad: bool,
+}
+
+impl MatchFormat {
+    pub fn rules(self) -> FormatRules {
+        match self {
+            MatchFormat::TwoSetsMatchTiebreak => FormatRules {
+                sets_to_win: 2,
+                games_per_set: 6,
+                tiebreak_at: 6,
+                deciding_match_tiebreak: true,
+                no_ad: false,
+            },
+            MatchFormat::BestOfThreeSets => FormatRules {
+                sets_to_win: 2,
+                games_per_set: 6,
+                tiebreak_at: 6,
+                deciding_match_tiebreak: false,
+                no_ad: false,
+            },
+            MatchFormat::Fast4 => FormatRules {
+                sets_to_win: 2,
+                games_per_set: 4,
+                tiebreak_at: 3,
+                deciding_match_tiebreak: false,
+                no_ad: true,
+            },
+            MatchFormat::ProSet => FormatRules {
+                sets_to_win: 1,
+                games_per_set: 8,
+                tiebreak_at: 8,
+                deciding_match_tiebreak: false,
+                no_ad: false,
+            },
+            MatchFormat::NoAd => FormatRules {
+                sets_to_win: 2,
+                games_per_set: 6,
+                tiebreak_at: 6,
+                deciding_match_tiebreak: true,
+                no_ad: true,
+            },
+        }
+    }
+}
+
+impl FormatRules {
+    pub fn max_sets(&self) -> usize {
+        self.sets_to_win * 2 - 1
+    }
+
+    // A deciding match tiebreak is recorded as a set of points rather than games
+    pub fn is_match_tiebreak(&self, set_index: usize) -> bool {
+        self.deciding_match_tiebreak && set_index == self.max_sets() - 1
+    }
+}
+
+// Games won by each player in one set, or points for a match tiebreak
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, FromRow)]
+pub struct SetScore {
+    pub player_one: i64,
+    pub player_two: i64,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Side {
+    PlayerOne,
+    PlayerTwo,
+}
+
+impl SetScore {
+    pub fn winner(&self) -> Option<Side> {
+        if self.player_one > self.player_two {
+            Some(Side::PlayerOne)
+        } else if self.player_two > self.player_one {
+            Some(Side::PlayerTwo)
+        } else {
+            None
+        }
+    }
+
+    // The score from the given player's point of view as (for, against)
+    pub fn for_side(&self, side: Side) -> (i64, i64) {
+        match side {
+            Side::PlayerOne => (self.player_one, self.player_two),
+            Side::PlayerTwo => (self.player_two, self.player_one),
+        }
+    }
+}
+
+pub fn sets_won(sets: &[SetScore]) -> (usize, usize) {
+    sets.iter()
+        .fold((0, 0), |(player_one, player_two), set| match set.winner() {
+            Some(Side::PlayerOne) => (player_one + 1, player_two),
+            Some(Side::PlayerTwo) => (player_one, player_two + 1),
+            None => (player_one, player_two),
+        })
+}
+
+pub fn match_winner(rules: &FormatRules, sets: &[SetScore]) -> Option<Side> {
+    let (player_one, player_two) = sets_won(sets);
+    if player_one >= rules.sets_to_win {
+        Some(Side::PlayerOne)
+    } else if player_two >= rules.sets_to_win {
+        Some(Side::PlayerTwo)
+    } else {
+        None
+    }
+}
+
+// Every problem found with a submitted score, returned to the client as a 422
+#[derive(Serialize, Debug, Error)]
+#[error("Invalid score")]
+pub struct ScoreValidationError {
+    pub errors: Vec<SetError>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SetError {
+    // 1-based set number, or None when the problem is with the match as a whole
+    pub set: Option<usize>,
+    pub reason: String,
+}
+
+const MATCH_TIEBREAK_POINTS: i64 = 10;
+
+// Checks each set against the format. When `complete` is false the final set
+// is allowed to be unfinished, e.g. for matches that were abandoned part way.
+pub fn validate_score(
+    rules: &FormatRules,
+    sets: &[SetScore],
+    complete: bool,
+) -> Result<(), ScoreValidationError> {
+    let mut errors = vec![];
+    if sets.len() > rules.max_sets() {
+        errors.push(SetError {
+            set: None,
+            reason: format!("This format has at most {} sets", rules.max_sets()),
+        });
+    }
+
+    let mut sets_won = (0, 0);
+    for (set_index, set) in sets.iter().enumerate() {
+        let set_number = Some(set_index + 1);
+        if sets_won.0 >= rules.sets_to_win || sets_won.1 >= rules.sets_to_win {
+            errors.push(SetError {
+                set: set_number,
+                reason: "The match was already decided before this set".to_string(),
+            });
+            continue;
+        }
+        let unfinished_allowed = !complete && set_index == sets.len() - 1;
+        let result = if rules.is_match_tiebreak(set_index) {
+            validate_match_tiebreak(set, unfinished_allowed)
+        } else {
+            validate_set(rules, set, unfinished_allowed)
+        };
+        // Invalid sets aren't counted so later sets are still checked sensibly
+        match (result, set.winner()) {
+            (Err(reason), _) => errors.push(SetError {
+                set: set_number,
+                reason,
+            }),
+            (Ok(()), Some(Side::PlayerOne)) => sets_won.0 += 1,
+            (Ok(()), Some(Side::PlayerTwo)) => sets_won.1 += 1,
+            (Ok(()), None) => {}
+        }
+    }
+
+    if complete && errors.is_empty() && match_winner(rules, sets).is_none() {
+        errors.push(SetError {
+            set: None,
+            reason: format!(
+                "A completed match needs a player to win {} sets",
+                rules.sets_to_win
+            ),
+        });
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ScoreValidationError { errors })
+    }
+}
+
+// Whether a set was played to a finish, e.g. not the set a player retired in
+pub fn is_finished_set(rules: &FormatRules, set_index: usize, set: &SetScore) -> bool {
+    if rules.is_match_tiebreak(set_index) {
+        validate_match_tiebreak(set, false).is_ok()
+    } else {
+        validate_set(rules, set, false).is_ok()
+    }
+}
+
+fn validate_set(
+    rules: &FormatRules,
+    set: &SetScore,
+    unfinished_allowed: bool,
+) -> Result<(), String> {
+    if set.player_one < 0 || set.player_two < 0 {
+        return Err("Games cannot be negative".to_string());
+    }
+    let winner_games = set.player_one.max(set.player_two);
+    let loser_games = set.player_one.min(set.player_two);
+    let games = rules.games_per_set;
+
+    // Formats like Fast4 play a sudden tiebreak one game before the set target
+    let finished = if rules.tiebreak_at < games {
+        winner_games == games && loser_games <= rules.tiebreak_at
+    } else {
+        (winner_games == games && loser_games <= games - 2)
+            || (winner_games == games + 1 && loser_games >= games - 1 && loser_games <= games)
+    };
+    if finished {
+        return Ok(());
+    }
+
+    let in_progress = if rules.tiebreak_at < games {
+        winner_games < games
+    } else {
+        winner_games < games || (winner_games == games && loser_games >= games - 1)
+    };
+    if unfinished_allowed && in_progress {
+        return Ok(());
+    }
+
+    Err(format!(
+        "{}-{} is not a valid set score, a set is won {}-x by two clear games or {}-{} on a tiebreak",
+        set.player_one,
+        set.player_two,
+        games,
+        rules.tiebreak_at + 1,
+        rules.tiebreak_at
+    ))
+}
+
+fn validate_match_tiebreak(set: &SetScore, unfinished_allowed: bool) -> Result<(), String> {
+    if set.player_one < 0 || set.player_two < 0 {
+        return Err("Points cannot be negative".to_string());
+    }
+    let winner_points = set.player_one.max(set.player_two);
+    let loser_points = set.player_one.min(set.player_two);
+    let margin = winner_points - loser_points;
+
+    let finished = winner_points >= MATCH_TIEBREAK_POINTS
+        && margin >= 2
+        && (winner_points == MATCH_TIEBREAK_POINTS || margin == 2);
+    if finished {
+        return Ok(());
+    }
+
+    let in_progress = winner_points < MATCH_TIEBREAK_POINTS || margin < 2;
+    if unfinished_allowed && in_progress {
+        return Ok(());
+    }
+
+    Err(format!(
+        "{}-{} is not a valid match tiebreak, it is won at {} points by two clear points",
+        set.player_one, set.player_two, MATCH_TIEBREAK_POINTS
+    ))
+}
+
+// Works out the winner for a result of any outcome, checking the score is
+// consistent with it. Retirements and walkovers need the winner to be given.
+pub fn resolve_result(
+    rules: &FormatRules,
+    outcome: ResultOutcome,
+    sets: &[SetScore],
+    declared_winner: Option<Side>,
+    complete: bool,
+) -> Result<Option<Side>, ScoreValidationError> {
+    let match_error = |reason: &str| ScoreValidationError {
+        errors: vec![SetError {
+            set: None,
+            reason: reason.to_string(),
+        }],
+    };
+    match outcome {
+        ResultOutcome::Played => {
+            validate_score(rules, sets, complete)?;
+            Ok(if complete {
+                match_winner(rules, sets)
+            } else {
+                None
+            })
+        }
+        ResultOutcome::Retired => {
+            let Some(winner) = declared_winner else {
+                return Err(match_error("A retirement needs the winner to be given"));
+            };
+            validate_score(rules, sets, false)?;
+            // Otherwise the loser of a finished match could be given the win
+            if validate_score(rules, sets, true).is_ok() {
+                return Err(match_error(
+                    "A retirement can't have a score that already decides the match",
+                ));
+            }
+            Ok(Some(winner))
+        }
+        ResultOutcome::Walkover => {
+            let Some(winner) = declared_winner else {
+                return Err(match_error("A walkover needs the winner to be given"));
+            };
+            if !sets.is_empty() {
+                return Err(match_error("A walkover can't have a score"));
+            }
+            Ok(Some(winner))
+        }
+        ResultOutcome::DoubleWalkover | ResultOutcome::Cancelled => {
+            if !sets.is_empty() {
+                return Err(match_error(
+                    "A double walkover or cancelled match can't have a score",
+                ));
+            }
+            Ok(None)
+        }
+    }
+}
+
+// A short description of a result for messages, e.g. "6-4 3-6 10-8" or "walkover"
+pub fn describe_score(outcome: ResultOutcome, sets: &[SetScore]) -> String {
+    let score = sets
+        .iter()
+        .map(|set| format!("{}-{}", set.player_one, set.player_two))
+        .collect::<Vec<_>>()
+        .join(" ");
+    match outcome {
+        ResultOutcome::Played => score,
+        ResultOutcome::Retired => format!("{} ret.", score),
+        ResultOutcome::Walkover => "walkover".to_string(),
+        ResultOutcome::DoubleWalkover => "double walkover".to_string(),
+        ResultOutcome::Cancelled => "cancelled".to_string(),
+    }
+}
@@ -0,0 +1,320 @@
+use super::scoring::{is_finished_set, MatchFormat, ResultOutcome, Side};
+use super::{MatchResult, ResultStatus};
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+// Ways of separating players who finish level on points, applied in order
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TieBreaker {
+    HeadToHead,
+    SetDifference,
+    GameDifference,
+    GamesWon,
+}
+
+impl FromStr for TieBreaker {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "head_to_head" => Ok(TieBreaker::HeadToHead),
+            "set_difference" => Ok(TieBreaker::SetDifference),
+            "game_difference" => Ok(TieBreaker::GameDifference),
+            "games_won" => Ok(TieBreaker::GamesWon),
+            _ => Err(anyhow::anyhow!("Unknown tie-breaker {}", value)),
+        }
+    }
+}
+
+impl From<TieBreaker> for String {
+    fn from(val: TieBreaker) -> Self {
+        match val {
+            TieBreaker::HeadToHead => "head_to_head".to_string(),
+            TieBreaker::SetDifference => "set_difference".to_string(),
+            TieBreaker::GameDifference => "game_difference".to_string(),
+            TieBreaker::GamesWon => "games_won".to_string(),
+        }
+    }
+}
+
+// How a league awards points, as stored against the league
+#[derive(FromRow)]
+pub struct PointsSchemeRow {
+    pub points_per_win: i64,
+    pub points_per_loss: i64,
+    pub points_per_set: i64,
+    pub points_per_walkover: i64,
+    pub points_per_retirement: i64,
+    pub points_per_walkover_loss: i64,
+    pub points_per_double_walkover: i64,
+    pub tie_breakers: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PointsScheme {
+    pub points_per_win: i64,
+    pub points_per_loss: i64,
+    pub points_per_set: i64,
+    // Awarded to the player who receives a walkover
+    pub points_per_walkover: i64,
+    // Awarded to the player who retires instead of the usual loss points
+    pub points_per_retirement: i64,
+    // Awarded to the player who fails to turn up, can be negative
+    pub points_per_walkover_loss: i64,
+    // Awarded to each player when neither turns up
+    pub points_per_double_walkover: i64,
+    pub tie_breakers: Vec<TieBreaker>,
+}
+
+impl TryFrom<PointsSchemeRow> for PointsScheme {
+    type Error = anyhow::Error;
+
+    fn try_from(row: PointsSchemeRow) -> Result<Self, Self::Error> {
+        let tie_breakers = row
+            .tie_breakers
+            .split(',')
+            .filter(|value| !value.is_empty())
+            .map(TieBreaker::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(PointsScheme {
+            points_per_win: row.points_per_win,
+            points_per_loss: row.points_per_loss,
+            points_per_set: row.points_per_set,
+            points_per_walkover: row.points_per_walkover,
+            points_per_retirement: row.points_per_retirement,
+            points_per_walkover_loss: row.points_per_walkover_loss,
+            points_per_double_walkover: row.points_per_double_walkover,
+            tie_breakers,
+        })
+    }
+}
+
+impl PointsScheme {
+    pub fn tie_breakers_string(&self) -> String {
+        self.tie_breakers
+            .iter()
+            .map(|tie_breaker| String::from(*tie_breaker))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LeagueTableRow {
+    pub player_id: i64,
+    pub name: String,
+    pub played: i64,
+    pub matches_won: i64,
+    pub matches_lost: i64,
+    pub sets_won: i64,
+    pub sets_lost: i64,
+    pub games_won: i64,
+    pub games_lost: i64,
+    pub points: i64,
+    // The tie-breaker that decided this position when level on points
+    pub tie_breaker: Option<TieBreaker>,
+}
+
+impl LeagueTableRow {
+    pub fn new(player_id: i64, name: String) -> Self {
+        Self {
+            player_id,
+            name,
+            played: 0,
+            matches_won: 0,
+            matches_lost: 0,
+            sets_won: 0,
+            sets_lost: 0,
+            games_won: 0,
+            games_lost: 0,
+            points: 0,
+            tie_breaker: None,
+        }
+    }
+}
+
+pub fn compute_league_table(
+    league_players: Vec<i64>,
+    player_map: HashMap<i64, String>,
+    completed_fixures: &[MatchResult],
+    match_format: MatchFormat,
+    points_scheme: &PointsScheme,
+) -> Vec<LeagueTableRow> {
+    let rules = match_format.rules();
+    let mut league_table = vec![];
+    for player_id in league_players {
+        let mut row = LeagueTableRow::new(
+            player_id,
+            player_map
+                .get(&player_id)
+                .expect("Player not found in map")
+                .to_owned(),
+        );
+        // Loop through fixtures
+        for fixture in completed_fixures {
+            let Some(side) = side_of(fixture, player_id) else {
+                continue;
+            };
+            if !is_confirmed(fixture) {
+                continue;
+            }
+            // Match logic
+            let won = fixture.winner == Some(player_id);
+            let outcome = fixture.outcome.unwrap_or_default();
+            if outcome == ResultOutcome::Cancelled {
+                continue;
+            }
+            if won {
+                row.matches_won += 1;
+            } else {
+                row.matches_lost += 1;
+            }
+            row.points += match (outcome, won) {
+                (ResultOutcome::Walkover, true) => points_scheme.points_per_walkover,
+                (ResultOutcome::Walkover, false) => points_scheme.points_per_walkover_loss,
+                (ResultOutcome::DoubleWalkover, _) => points_scheme.points_per_double_walkover,
+                (ResultOutcome::Retired, false) => points_scheme.points_per_retirement,
+                (_, true) => points_scheme.points_per_win,
+                (_, false) => points_scheme.points_per_loss,
+            };
+            for (set_index, set) in fixture.sets.iter().enumerate() {
+                let set_winner = set
+                    .winner()
+                    .filter(|_| is_finished_set(&rules, set_index, set));
+                match set_winner {
+                    Some(winner) if winner == side => {
+                        row.sets_won += 1;
+                        row.points += points_scheme.points_per_set;
+                    }
+                    Some(_) => row.sets_lost += 1,
+                    None => {}
+                }
+                // Match tiebreak points aren't games
+                if !rules.is_match_tiebreak(set_index) {
+                    let (games_won, games_lost) = set.for_side(side);
+                    row.games_won += games_won;
+                    row.games_lost += games_lost;
+                }
+            }
+        }
+        row.played = row.matches_won + row.matches_lost;
+        league_table.push(row);
+    }
+
+    league_table.sort_by_key(|row| Reverse(row.points));
+    let mut sorted = vec![];
+    for group in split_by_key(league_table, |row| row.points) {
+        sorted.extend(break_ties(
+            group,
+            &points_scheme.tie_breakers,
+            completed_fixures,
+        ));
+    }
+    sorted
+}
+
+// Results waiting for the opponent or an admin don't count yet
+fn is_confirmed(fixture: &MatchResult) -> bool {
+    fixture.result_status == Some(ResultStatus::Confirmed)
+}
+
+fn side_of(fixture: &MatchResult, player_id: i64) -> Option<Side> {
+    if fixture.player_one_id == player_id {
+        Some(Side::PlayerOne)
+    } else if fixture.player_two_id == player_id {
+        Some(Side::PlayerTwo)
+    } else {
+        None
+    }
+}
+
+// Orders players level on points using each tie-breaker in turn until they're
+// separated. Anyone still level after every tie-breaker keeps their order.
+fn break_ties(
+    group: Vec<LeagueTableRow>,
+    tie_breakers: &[TieBreaker],
+    completed_fixtures: &[MatchResult],
+) -> Vec<LeagueTableRow> {
+    let Some((&tie_breaker, remaining)) = tie_breakers.split_first() else {
+        return group;
+    };
+    if group.len() < 2 {
+        return group;
+    }
+
+    let keys = tie_breaker_keys(&group, tie_breaker, completed_fixtures);
+    let mut group: Vec<(i64, LeagueTableRow)> = group
+        .into_iter()
+        .map(|row| (keys[&row.player_id], row))
+        .collect();
+    group.sort_by_key(|(key, _)| Reverse(*key));
+
+    let sub_groups = split_by_key(group, |(key, _)| *key);
+    let separated = sub_groups.len() > 1;
+    let mut sorted = vec![];
+    for sub_group in sub_groups {
+        let mut sub_group: Vec<LeagueTableRow> =
+            sub_group.into_iter().map(|(_, row)| row).collect();
+        if separated && sub_group.len() == 1 {
+            sub_group[0].tie_breaker = Some(tie_breaker);
+        }
+        sorted.extend(break_ties(sub_group, remaining, completed_fixtures));
+    }
+    sorted
+}
+
+fn tie_breaker_keys(
+    group: &[LeagueTableRow],
+    tie_breaker: TieBreaker,
+    completed_fixtures: &[MatchResult],
+) -> HashMap<i64, i64> {
+    match tie_breaker {
+        // Matches won against the other players in the tied group only
+        TieBreaker::HeadToHead => {
+            let tied: HashSet<i64> = group.iter().map(|row| row.player_id).collect();
+            let mut wins: HashMap<i64, i64> = tied.iter().map(|&id| (id, 0)).collect();
+            for fixture in completed_fixtures {
+                if is_confirmed(fixture)
+                    && tied.contains(&fixture.player_one_id)
+                    && tied.contains(&fixture.player_two_id)
+                {
+                    if let Some(winner) = fixture.winner.and_then(|winner| wins.get_mut(&winner)) {
+                        *winner += 1;
+                    }
+                }
+            }
+            wins
+        }
+        TieBreaker::SetDifference => group
+            .iter()
+            .map(|row| (row.player_id, row.sets_won - row.sets_lost))
+            .collect(),
+        TieBreaker::GameDifference => group
+            .iter()
+            .map(|row| (row.player_id, row.games_won - row.games_lost))
+            .collect(),
+        TieBreaker::GamesWon => group
+            .iter()
+            .map(|row| (row.player_id, row.games_won))
+            .collect(),
+    }
+}
+
+// Splits an already sorted list into runs sharing the same key
+fn split_by_key<T>(items: Vec<T>, key: impl Fn(&T) -> i64) -> Vec<Vec<T>> {
+    let mut groups: Vec<Vec<T>> = vec![];
+    let mut last_key = None;
+    for item in items {
+        let item_key = key(&item);
+        match groups.last_mut() {
+            Some(group) if last_key == Some(item_key) => group.push(item),
+            _ => groups.push(vec![item]),
+        }
+        last_key = Some(item_key);
+    }
+    groups
+}
@@ -1,38 +1,372 @@
-use crate::default_route_handlers::{ErrorList, Username};
+use crate::config::{CookieSameSite, SessionConfig};
+use crate::default_route_handlers::{ErrorList, CODE_LIFETIME_SECONDS, CODE_LIMIT_WINDOW_SECONDS};
 use crate::AppState;
 use chrono::Utc;
-use cookie::Cookie;
-use http::HeaderMap;
-use std::sync::Arc;
+use cookie::{time::Duration, Cookie, SameSite};
+use http::{HeaderMap, Method};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use std::fmt;
+use std::{sync::Arc, time};
+use subtle::ConstantTimeEq;
 use tracing::{event, Level};
 
-pub async fn validate_cookie(
-    headers: &HeaderMap,
-    state: Arc<AppState>,
-) -> Result<Username, anyhow::Error> {
-    if let Some(cookies) = headers.get("cookie") {
-        for cookie_string in cookies.to_str().unwrap().split(';') {
-            let cookie = Cookie::parse(cookie_string)?;
-            if cookie.name() == "session-key" {
-                let session = sqlx::query_as::<_, Username>(
-                    "SELECT username FROM SESSIONS WHERE session_key=? AND expiry > ?",
-                )
-                .bind(cookie.value())
-                .bind(Utc::now().timestamp())
-                .fetch_optional(&state.db_connection_pool)
-                .await?;
-                if let Some(username) = session {
-                    return Ok(username);
+// Roles in order of increasing privilege, stored as users.auth_level. Accounts
+// which haven't verified their email yet sit below player at 0.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Player,
+    Captain,
+    Organiser,
+    SuperAdmin,
+}
+
+impl Role {
+    pub fn auth_level(self) -> i64 {
+        match self {
+            Role::Player => 50,
+            Role::Captain => 60,
+            Role::Organiser => 80,
+            Role::SuperAdmin => 100,
+        }
+    }
+}
+
+// What an API token may be used for. Role checks still apply on top, so a
+// token never gets more than its owner could do.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum TokenScope {
+    ReadOnly,
+    ResultsWrite,
+    Admin,
+}
+
+// Routes a read only token may fetch. Being a GET isn't enough on its own, so
+// new routes have to be added here before read only tokens can use them.
+// Entries ending in / match any path beneath them.
+const READ_ONLY_PATHS: &[&str] = &[
+    "/api/me",
+    "/api/players",
+    "/api/player/",
+    "/api/admin/leagueTable/",
+    "/api/admin/disputedResults",
+    "/api/admin/linkRequests",
+    "/api/admin/outbox",
+    "/api/admin/twoFactorPolicy",
+    "/api/promotionRelegation/",
+];
+
+impl TokenScope {
+    // Account routes such as managing tokens or passwords need a browser session
+    pub fn allows(self, method: &Method, path: &str) -> bool {
+        if path.starts_with("/account/") {
+            return false;
+        }
+        let read_only = matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+            && READ_ONLY_PATHS.iter().any(|&allowed| {
+                if allowed.ends_with('/') {
+                    path.starts_with(allowed)
+                } else {
+                    path == allowed
                 }
-                event!(
-                    Level::INFO,
-                    "Session key cookie was found but did not match a valid session"
-                );
-                return Err(ErrorList::Unauthorised.into());
+            });
+        match self {
+            TokenScope::ReadOnly => read_only,
+            TokenScope::ResultsWrite => {
+                read_only
+                    || path == "/api/result"
+                    || path == "/api/myResult"
+                    || path.starts_with("/api/result/")
             }
+            TokenScope::Admin => true,
         }
     }
+}
+
+impl fmt::Display for TokenScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TokenScope::ReadOnly => "read only",
+            TokenScope::ResultsWrite => "results write",
+            TokenScope::Admin => "admin",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::Player => "player",
+            Role::Captain => "captain",
+            Role::Organiser => "league organiser",
+            Role::SuperAdmin => "super-admin",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub async fn get_auth_level(username: &str, state: Arc<AppState>) -> Result<i64, anyhow::Error> {
+    let auth_level: Option<i64> =
+        sqlx::query_scalar("SELECT auth_level FROM users WHERE username=?")
+            .bind(username)
+            .fetch_optional(&state.db_connection_pool)
+            .await?;
+    auth_level.ok_or(ErrorList::Unauthorised.into())
+}
 
-    event!(Level::INFO, "No session key cookie was found");
+pub const SESSION_COOKIE: &str = "session-key";
+pub const CSRF_COOKIE: &str = "csrf-token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+pub const CHALLENGE_COOKIE: &str = "login-challenge";
+
+// The value of the named cookie from the request, if one was sent
+pub fn get_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    let cookies = headers.get("cookie")?.to_str().ok()?;
+    cookies
+        .split(';')
+        .filter_map(|cookie_string| Cookie::parse(cookie_string.trim()).ok())
+        .find(|cookie| cookie.name() == name)
+        .map(|cookie| cookie.value().to_string())
+}
+
+// Session keys and API tokens are stored by their hash so a copy of the
+// database can't be used to sign in as anyone
+pub fn hash_token(session_key: &str) -> String {
+    hex::encode(Sha256::digest(session_key.as_bytes()))
+}
+
+// The stored hash of the session key from the request's cookie
+pub fn get_session_hash(headers: &HeaderMap) -> Option<String> {
+    get_cookie(headers, SESSION_COOKIE).map(|session_key| hash_token(&session_key))
+}
+
+// Whether the user is missing the second factor that super-admins have made
+// compulsory for the admin roles
+pub async fn needs_two_factor(username: &str, state: Arc<AppState>) -> Result<bool, anyhow::Error> {
+    let needs_two_factor: Option<bool> = sqlx::query_scalar(
+        "SELECT totp_enabled=0 AND EXISTS(SELECT 1 FROM settings WHERE name='require_admin_two_factor' AND value='true')
+        FROM users WHERE username=?",
+    )
+    .bind(username)
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+    needs_two_factor.ok_or(ErrorList::Unauthorised.into())
+}
+
+// The token from an `Authorization: Bearer` header, if one was sent
+pub fn get_bearer_token(headers: &HeaderMap) -> Option<String> {
+    let authorization = headers.get(http::header::AUTHORIZATION)?.to_str().ok()?;
+    authorization
+        .strip_prefix("Bearer ")
+        .map(|token| token.trim().to_string())
+}
+
+#[derive(FromRow)]
+pub struct ApiTokenSession {
+    pub username: String,
+    pub scope: TokenScope,
+}
+
+// Checks the token exists and hasn't expired, recording that it was used
+pub async fn validate_api_token(
+    token: &str,
+    state: Arc<AppState>,
+) -> Result<ApiTokenSession, anyhow::Error> {
+    let now = Utc::now().timestamp();
+    let session = sqlx::query_as::<_, ApiTokenSession>(
+        "UPDATE api_tokens SET last_used_ts=?
+        WHERE token_hash=? AND (expiry_ts IS NULL OR expiry_ts > ?)
+        RETURNING username,scope",
+    )
+    .bind(now)
+    .bind(hash_token(token))
+    .bind(now)
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+    session.ok_or_else(|| {
+        event!(Level::INFO, "API token did not match a valid token");
+        ErrorList::Unauthorised.into()
+    })
+}
+
+#[derive(FromRow)]
+pub struct ActiveSession {
+    pub username: String,
+    pub remember_me: bool,
+    pub csrf_token: Option<String>,
+}
+
+// The session cookie, kept for max_age or until the browser closes if None
+pub fn session_cookie(
+    config: &SessionConfig,
+    session_key: &str,
+    max_age: Option<Duration>,
+) -> Cookie<'static> {
+    build_cookie(config, SESSION_COOKIE, session_key, max_age, true)
+}
+
+// The CSRF token is readable by scripts so they can echo it in a header
+pub fn csrf_cookie(
+    config: &SessionConfig,
+    csrf_token: &str,
+    max_age: Option<Duration>,
+) -> Cookie<'static> {
+    build_cookie(config, CSRF_COOKIE, csrf_token, max_age, false)
+}
+
+// Identifies a login waiting for its second factor
+pub fn challenge_cookie(
+    config: &SessionConfig,
+    challenge: &str,
+    max_age: Option<Duration>,
+) -> Cookie<'static> {
+    build_cookie(config, CHALLENGE_COOKIE, challenge, max_age, true)
+}
+
+fn build_cookie(
+    config: &SessionConfig,
+    name: &'static str,
+    value: &str,
+    max_age: Option<Duration>,
+    http_only: bool,
+) -> Cookie<'static> {
+    let same_site = match config.same_site {
+        CookieSameSite::Strict => SameSite::Strict,
+        CookieSameSite::Lax => SameSite::Lax,
+        CookieSameSite::None => SameSite::None,
+    };
+    let mut cookie = Cookie::build((name, value.to_string()))
+        .http_only(http_only)
+        .secure(config.secure)
+        .same_site(same_site)
+        .path("/");
+    if let Some(domain) = &config.cookie_domain {
+        cookie = cookie.domain(domain.clone());
+    }
+    if let Some(max_age) = max_age {
+        cookie = cookie.max_age(max_age);
+    }
+    cookie.build()
+}
+
+// Requests which change state must echo the session's CSRF token in a header,
+// which a cross-site form or script can't do
+pub fn check_csrf(
+    method: &Method,
+    headers: &HeaderMap,
+    session: &ActiveSession,
+) -> Result<(), ErrorList> {
+    if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Ok(());
+    }
+    let provided = headers
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match (provided, &session.csrf_token) {
+        (Some(provided), Some(expected))
+            if bool::from(provided.as_bytes().ct_eq(expected.as_bytes())) =>
+        {
+            Ok(())
+        }
+        _ => Err(ErrorList::InvalidCsrfToken),
+    }
+}
+
+// Checks the session is valid and slides its expiry forward now it's been used
+pub async fn validate_cookie(
+    headers: &HeaderMap,
+    state: Arc<AppState>,
+) -> Result<ActiveSession, anyhow::Error> {
+    let Some(session_hash) = get_session_hash(headers) else {
+        event!(Level::INFO, "No session key cookie was found");
+        return Err(ErrorList::Unauthorised.into());
+    };
+
+    let now = Utc::now().timestamp();
+    let session_config = &state.config.session;
+    let session = sqlx::query_as::<_, ActiveSession>(
+        "UPDATE sessions SET
+        last_seen_ts=?,
+        expiry=CASE WHEN remember_me=1 THEN ? ELSE MIN(?, COALESCE(created_ts, ?) + ?) END
+        WHERE session_key=? AND expiry > ?
+        RETURNING username,remember_me,csrf_token",
+    )
+    .bind(now)
+    .bind(session_config.expiry(true, now, now))
+    .bind(now + session_config.idle_timeout_minutes * 60)
+    .bind(now)
+    .bind(session_config.lifetime_hours * 3600)
+    .bind(&session_hash)
+    .bind(now)
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+    if let Some(session) = session {
+        return Ok(session);
+    }
+    event!(
+        Level::INFO,
+        "Session key cookie was found but did not match a valid session"
+    );
     Err(ErrorList::Unauthorised.into())
 }
+
+// Periodically removes expired sessions and codes which can no longer be used,
+// and the copies of codes left in undelivered emails
+pub async fn run_session_cleanup(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(time::Duration::from_secs(
+        state.config.session.cleanup_interval_minutes * 60,
+    ));
+    loop {
+        interval.tick().await;
+        if let Err(e) = purge_expired(state.clone()).await {
+            event!(Level::ERROR, "Session cleanup failed: {}", e);
+        }
+    }
+}
+
+async fn purge_expired(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let now = Utc::now().timestamp();
+    let sessions = sqlx::query("DELETE FROM sessions WHERE expiry <= ?")
+        .bind(now)
+        .execute(&state.db_connection_pool)
+        .await?;
+    sqlx::query("DELETE FROM api_tokens WHERE expiry_ts <= ?")
+        .bind(now)
+        .execute(&state.db_connection_pool)
+        .await?;
+    sqlx::query("DELETE FROM login_challenges WHERE expiry <= ?")
+        .bind(now)
+        .execute(&state.db_connection_pool)
+        .await?;
+    // Code timestamps are stored in text columns so compare them as numbers.
+    // Codes still inside the rate limit window are kept so they keep counting.
+    let codes = sqlx::query(
+        "DELETE FROM codes WHERE (used=1 OR CAST(expiry_ts AS INTEGER) <= ?)
+        AND CAST(created_ts AS INTEGER) <= ?",
+    )
+    .bind(now)
+    .bind(now - CODE_LIMIT_WINDOW_SECONDS)
+    .execute(&state.db_connection_pool)
+    .await?;
+    // Dead messages can be retried while their codes still work, after that
+    // the bodies are only a copy of credentials nobody received
+    sqlx::query(
+        "UPDATE outbox SET text_body='', html_body=''
+        WHERE status!='pending' AND text_body!='' AND created_ts <= ?",
+    )
+    .bind(now - CODE_LIFETIME_SECONDS)
+    .execute(&state.db_connection_pool)
+    .await?;
+    event!(
+        Level::INFO,
+        "Purged {} expired sessions and {} used or expired codes",
+        sessions.rows_affected(),
+        codes.rows_affected()
+    );
+    Ok(())
+}
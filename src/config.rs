@@ -3,9 +3,12 @@ use lettre::{
         authentication::{Credentials, Mechanism},
         PoolConfig,
     },
-    SmtpTransport,
+    FileTransport, SmtpTransport,
 };
 use serde::Deserialize;
+
+use crate::email::Mailer;
+use std::net::IpAddr;
 use std::str::FromStr;
 use std::{fs::File, io::prelude::*};
 
@@ -17,7 +20,7 @@ use sqlx::{
 #[derive(Clone)]
 pub struct AppState {
     pub db_connection_pool: Pool<Sqlite>,
-    pub email_connection_pool: SmtpTransport,
+    pub email_connection_pool: Mailer,
     pub config: Config,
 }
 
@@ -26,6 +29,99 @@ pub struct Config {
     pub server: ServerConfig,
     pub database: DatabaseConfig,
     pub email: SmtpConfig,
+    #[serde(default)]
+    pub security: SecurityConfig,
+    #[serde(default)]
+    pub session: SessionConfig,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SessionConfig {
+    // Ordinary sessions end after this long unused, pushed back each time
+    // they're used, but never last longer than the lifetime
+    pub idle_timeout_minutes: i64,
+    pub lifetime_hours: i64,
+    // "Remember me" sessions last this long since they were last used
+    pub remember_me_days: i64,
+    // How often expired sessions and codes are purged
+    pub cleanup_interval_minutes: u64,
+    // Attributes for the session and CSRF cookies. Browsers still accept
+    // secure cookies over plain http on localhost.
+    pub secure: bool,
+    pub same_site: CookieSameSite,
+    pub cookie_domain: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CookieSameSite {
+    Strict,
+    #[default]
+    Lax,
+    None,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_minutes: 120,
+            lifetime_hours: 24,
+            remember_me_days: 30,
+            cleanup_interval_minutes: 60,
+            secure: true,
+            same_site: CookieSameSite::default(),
+            cookie_domain: None,
+        }
+    }
+}
+
+impl SessionConfig {
+    // The expiry for a session created at created_ts and last used now
+    pub fn expiry(&self, remember_me: bool, created_ts: i64, now: i64) -> i64 {
+        if remember_me {
+            now + self.remember_me_days * 24 * 3600
+        } else {
+            (now + self.idle_timeout_minutes * 60).min(created_ts + self.lifetime_hours * 3600)
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SecurityConfig {
+    // Failed logins in a row before the account is locked
+    pub max_login_attempts: i64,
+    // The first lockout lasts this long, doubling with each lockout after it
+    pub lockout_base_seconds: i64,
+    pub max_lockout_seconds: i64,
+    // Requests allowed per IP within the window on the login and reset routes
+    pub rate_limit_requests: usize,
+    pub rate_limit_window_seconds: u64,
+    // Reverse proxies whose X-Forwarded-For or Forwarded headers give the
+    // client address. Without one every client behind the proxy shares a limit.
+    pub trusted_proxies: Vec<IpAddr>,
+    // Passwordless login by emailed link, and how long each link works for
+    pub magic_link_enabled: bool,
+    pub magic_link_minutes: i64,
+    // Password reset and login link emails each account can be sent per hour
+    pub codes_per_hour: i64,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            max_login_attempts: 5,
+            lockout_base_seconds: 60,
+            max_lockout_seconds: 3600,
+            rate_limit_requests: 10,
+            rate_limit_window_seconds: 60,
+            trusted_proxies: vec![],
+            magic_link_enabled: false,
+            magic_link_minutes: 15,
+            codes_per_hour: 3,
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -34,18 +130,67 @@ pub struct DatabaseConfig {
     pub pool_size: u32,
 }
 
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailTransport {
+    #[default]
+    Smtp,
+    File,
+}
+
 #[derive(Deserialize, Clone)]
 pub struct SmtpConfig {
     pub server_url: String,
     pub username: String,
     pub password: String,
     pub pool_size: u32,
+    #[serde(default)]
+    pub transport: EmailTransport,
+    // Where the file transport writes messages
+    #[serde(default = "default_email_directory")]
+    pub file_directory: String,
+    #[serde(default = "default_email_from")]
+    pub from: String,
+    // Outbox delivery, retried with exponential backoff until max_attempts
+    #[serde(default = "default_outbox_poll_seconds")]
+    pub outbox_poll_seconds: u64,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: i64,
+    #[serde(default = "default_retry_base_seconds")]
+    pub retry_base_seconds: i64,
+}
+
+fn default_email_directory() -> String {
+    "./emails".to_string()
+}
+
+fn default_email_from() -> String {
+    "Tennis Leagues <registration@tld.com>".to_string()
+}
+
+fn default_outbox_poll_seconds() -> u64 {
+    5
+}
+
+fn default_max_attempts() -> i64 {
+    5
+}
+
+fn default_retry_base_seconds() -> i64 {
+    30
 }
 
 #[derive(Deserialize, Clone)]
 pub struct ServerConfig {
     pub port: u16,
     pub request_timeout: u64,
+    // Where the site is reached from, used to build links in emails
+    #[serde(default = "default_public_url")]
+    pub public_url: String,
+}
+
+fn default_public_url() -> String {
+    "http://localhost:3001".to_string()
 }
 
 pub fn get_config() -> Config {
@@ -59,8 +204,13 @@ pub fn get_config() -> Config {
 }
 
 impl Config {
-    pub fn get_email_pool(&self) -> SmtpTransport {
-        SmtpTransport::starttls_relay(self.email.server_url.as_str())
+    pub fn get_email_pool(&self) -> Mailer {
+        if self.email.transport == EmailTransport::File {
+            std::fs::create_dir_all(&self.email.file_directory)
+                .expect("Unable to create email directory");
+            return Mailer::File(FileTransport::new(&self.email.file_directory));
+        }
+        let transport = SmtpTransport::starttls_relay(self.email.server_url.as_str())
             .expect("Unable to create email connection pool")
             // Add credentials for authentication
             .credentials(Credentials::new(
@@ -71,7 +221,8 @@ impl Config {
             .authentication(vec![Mechanism::Plain])
             // Connection pool settings
             .pool_config(PoolConfig::new().max_size(self.email.pool_size))
-            .build()
+            .build();
+        Mailer::Smtp(transport)
     }
 
     pub async fn get_db_pool(&self) -> Pool<Sqlite> {
@@ -1,36 +1,39 @@
 use axum::async_trait;
-use axum::extract::{FromRequestParts, Json, State};
+use axum::extract::{FromRequestParts, Json, Path, Query, State};
 use axum::response::IntoResponse;
 use axum::{http::StatusCode, response::Html};
 use chrono::Utc;
 use cookie::time::Duration;
-use cookie::Cookie;
 use http::header::HeaderMap;
 use http::{header, HeaderValue};
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{FromRow, Sqlite, Transaction};
 use std::sync::Arc;
 use thiserror::Error;
+use tracing::{event, Level};
 use validations::*;
 
+use crate::app_route_handlers::scoring::ScoreValidationError;
+use crate::auth::{
+    challenge_cookie, csrf_cookie, get_auth_level, get_cookie, get_session_hash, hash_token,
+    session_cookie, Role, TokenScope, CHALLENGE_COOKIE,
+};
+use crate::email::{
+    insert_outbox_message, queue_email, EmailTemplate, OutboxMessage, OutboxStatus,
+};
+use crate::totp;
 use crate::utilities::*;
 use crate::AppState;
 
-mod validations;
-
-// Wrapper to allow derived impl of FromRow
-#[derive(FromRow)]
-pub struct Username(pub String);
-
-// Wrapper to allow derived impl of FromRow
-#[derive(FromRow)]
-pub struct CodeAndEmail(pub String, pub String);
+pub mod validations;
 
 #[derive(Deserialize)]
 pub struct PasswordResetInitiateRequest(pub String);
 
 #[derive(Deserialize)]
 pub struct PasswordResetCompleteRequest {
+    // The code only works for the account it was sent to
+    pub email: String,
     pub code: String,
     pub password: String,
     pub confirm_password: String,
@@ -41,6 +44,7 @@ pub struct PasswordResetCompleteRequest {
 pub enum CodeType {
     EmailVerification,
     PasswordReset,
+    MagicLink,
 }
 
 impl From<CodeType> for String {
@@ -48,6 +52,7 @@ impl From<CodeType> for String {
         match val {
             CodeType::EmailVerification => "EmailVerification".to_string(),
             CodeType::PasswordReset => "PasswordReset".to_string(),
+            CodeType::MagicLink => "MagicLink".to_string(),
         }
     }
 }
@@ -58,18 +63,20 @@ pub struct AppError(anyhow::Error);
 // Errors specific to our app
 #[derive(Error, Debug)]
 pub enum ErrorList {
-    #[error("Email must contain an @, be greater than 3 characters and less than 300 characters")]
-    _InvalidEmail,
+    #[error(
+        "Email must be a valid address, greater than 3 characters and less than 300 characters"
+    )]
+    InvalidEmail,
     #[error("Password must be between 8 and 100 characters")]
     InvalidPassword,
     #[error("Username must be between 3 and 100 characters")]
-    _InvalidUsername,
+    InvalidUsername,
     #[error("Your passwords do not match")]
     NonMatchingPasswords,
     #[error("That email is already registered")]
-    _EmailAlreadyRegistered,
+    EmailAlreadyRegistered,
     #[error("That username is already registered")]
-    _UsernameAlreadyRegistered,
+    UsernameAlreadyRegistered,
     #[error("Incorrect password")]
     IncorrectPassword,
     #[error("Incorrect username")]
@@ -78,16 +85,346 @@ pub enum ErrorList {
     InvalidVerificationCode,
     #[error("Unauthorised")]
     Unauthorised,
+    #[error("This needs the {0} role or higher")]
+    InsufficientRole(Role),
+    #[error("There is no active season")]
+    NoActiveSeason,
+    #[error("Season not found")]
+    SeasonNotFound,
+    #[error("That season has already finished")]
+    SeasonAlreadyFinished,
+    #[error("Promotion and relegation can only be applied once the season has finished")]
+    SeasonNotFinished,
+    #[error("Season dates must be valid YYYY-MM-DD dates with the end after the start")]
+    InvalidSeasonDates,
+    #[error("Promotion and relegation places cannot be negative")]
+    InvalidMovementPlaces,
+    #[error("Promotion and relegation have already been applied for that season")]
+    MovementsAlreadyApplied,
+    #[error("League not found")]
+    LeagueNotFound,
+    #[error("Fixture not found")]
+    FixtureNotFound,
+    #[error("The winner must be one of the players in the fixture")]
+    WinnerNotInFixture,
+    #[error("Results can only be submitted once the match is finished")]
+    ResultNotCompleted,
+    #[error("Your account isn't linked to a player")]
+    NotLinkedToPlayer,
+    #[error("You can only submit or confirm results for your own fixtures")]
+    NotYourFixture,
+    #[error("That result has already been confirmed")]
+    ResultAlreadyConfirmed,
+    #[error("That result isn't waiting for confirmation")]
+    ResultNotPending,
+    #[error("A result has already been submitted for that fixture")]
+    ResultAlreadySubmitted,
+    #[error("You can't confirm or dispute a result you submitted")]
+    OwnResultSubmission,
+    #[error("That result isn't disputed")]
+    ResultNotDisputed,
+    #[error("User not found")]
+    UserNotFound,
+    #[error("Player not found")]
+    PlayerNotFound,
+    #[error("That player is already linked to an account")]
+    PlayerAlreadyLinked,
+    #[error("Your account is already linked to a player")]
+    AccountAlreadyLinked,
+    #[error("Invalid or expired invite code")]
+    InvalidInviteCode,
+    #[error("Link request not found or already dealt with")]
+    LinkRequestNotFound,
+    #[error("Outbox message not found, already sent or too old to retry")]
+    OutboxMessageNotFound,
+    #[error("Too many failed logins, this account is locked for {0} more seconds")]
+    AccountLocked(i64),
+    #[error("Too many requests, please wait and try again")]
+    TooManyRequests,
+    #[error("Session not found")]
+    SessionNotFound,
+    #[error("Missing or invalid CSRF token")]
+    InvalidCsrfToken,
+    #[error("API token not found")]
+    ApiTokenNotFound,
+    #[error("Token names must be between 1 and 100 characters")]
+    InvalidApiTokenName,
+    #[error("Token expiry must be a positive number of days")]
+    InvalidApiTokenExpiry,
+    #[error("This token's {0} scope doesn't allow that")]
+    TokenScopeDenied(TokenScope),
+    #[error("Two-factor authentication is already enabled")]
+    TwoFactorAlreadyEnabled,
+    #[error("Start two-factor setup before verifying a code")]
+    TwoFactorNotSetUp,
+    #[error("Two-factor authentication isn't enabled")]
+    TwoFactorNotEnabled,
+    #[error("Incorrect two-factor or recovery code")]
+    IncorrectTwoFactorCode,
+    #[error("Login expired or not found, please log in again")]
+    InvalidLoginChallenge,
+    #[error("Two-factor authentication must be enabled on captain and admin accounts")]
+    TwoFactorRequired,
+    #[error("Login links aren't enabled")]
+    MagicLinkDisabled,
+    #[error("Invalid or expired login link")]
+    InvalidMagicLink,
+    #[error("League names must be between 1 and 100 characters")]
+    InvalidLeagueName,
+    #[error("League tiers start at 1 for the top league")]
+    InvalidLeagueTier,
+    #[error("Another league already has that name")]
+    LeagueNameTaken,
+    #[error("Another league already has that tier")]
+    LeagueTierTaken,
+    #[error("Move the league's players to another league first")]
+    LeagueHasPlayers,
+    #[error("That league has fixtures, archive it instead")]
+    LeagueHasFixtures,
+    #[error("That league is archived")]
+    LeagueArchived,
+    #[error("Player names must be between 1 and 100 characters")]
+    InvalidPlayerName,
+    #[error("Phone numbers must have at least 6 digits and only contain digits, spaces, +, - and brackets")]
+    InvalidPhoneNumber,
+    #[error("That player has fixtures, make them inactive instead")]
+    PlayerHasFixtures,
 }
 
-// Convert every AppError into a status code and its display impl
+impl ErrorList {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorList::InvalidEmail
+            | ErrorList::InvalidPassword
+            | ErrorList::InvalidUsername
+            | ErrorList::NonMatchingPasswords
+            | ErrorList::InvalidSeasonDates
+            | ErrorList::InvalidMovementPlaces
+            | ErrorList::WinnerNotInFixture
+            | ErrorList::ResultNotCompleted
+            | ErrorList::InvalidApiTokenName
+            | ErrorList::InvalidApiTokenExpiry
+            | ErrorList::InvalidLeagueName
+            | ErrorList::InvalidLeagueTier
+            | ErrorList::InvalidPlayerName
+            | ErrorList::InvalidPhoneNumber => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorList::InvalidVerificationCode
+            | ErrorList::InvalidInviteCode
+            | ErrorList::InvalidMagicLink => StatusCode::BAD_REQUEST,
+            ErrorList::IncorrectPassword
+            | ErrorList::IncorrectUsername
+            | ErrorList::Unauthorised
+            | ErrorList::IncorrectTwoFactorCode
+            | ErrorList::InvalidLoginChallenge => StatusCode::UNAUTHORIZED,
+            ErrorList::InsufficientRole(_)
+            | ErrorList::NotLinkedToPlayer
+            | ErrorList::NotYourFixture
+            | ErrorList::OwnResultSubmission
+            | ErrorList::InvalidCsrfToken
+            | ErrorList::TokenScopeDenied(_)
+            | ErrorList::TwoFactorRequired => StatusCode::FORBIDDEN,
+            ErrorList::NoActiveSeason
+            | ErrorList::SeasonNotFound
+            | ErrorList::LeagueNotFound
+            | ErrorList::FixtureNotFound
+            | ErrorList::UserNotFound
+            | ErrorList::PlayerNotFound
+            | ErrorList::LinkRequestNotFound
+            | ErrorList::OutboxMessageNotFound
+            | ErrorList::SessionNotFound
+            | ErrorList::ApiTokenNotFound
+            | ErrorList::MagicLinkDisabled => StatusCode::NOT_FOUND,
+            ErrorList::EmailAlreadyRegistered
+            | ErrorList::UsernameAlreadyRegistered
+            | ErrorList::SeasonAlreadyFinished
+            | ErrorList::SeasonNotFinished
+            | ErrorList::MovementsAlreadyApplied
+            | ErrorList::ResultAlreadyConfirmed
+            | ErrorList::ResultNotPending
+            | ErrorList::ResultAlreadySubmitted
+            | ErrorList::ResultNotDisputed
+            | ErrorList::PlayerAlreadyLinked
+            | ErrorList::AccountAlreadyLinked
+            | ErrorList::TwoFactorAlreadyEnabled
+            | ErrorList::TwoFactorNotSetUp
+            | ErrorList::TwoFactorNotEnabled
+            | ErrorList::LeagueNameTaken
+            | ErrorList::LeagueTierTaken
+            | ErrorList::LeagueHasPlayers
+            | ErrorList::LeagueHasFixtures
+            | ErrorList::LeagueArchived
+            | ErrorList::PlayerHasFixtures => StatusCode::CONFLICT,
+            ErrorList::AccountLocked(_) => StatusCode::LOCKED,
+            ErrorList::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    // Stable and machine readable codes that clients can rely on, so they're
+    // written out rather than derived from the variant names
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorList::InvalidEmail => "invalid_email",
+            ErrorList::InvalidPassword => "invalid_password",
+            ErrorList::InvalidUsername => "invalid_username",
+            ErrorList::NonMatchingPasswords => "non_matching_passwords",
+            ErrorList::EmailAlreadyRegistered => "email_already_registered",
+            ErrorList::UsernameAlreadyRegistered => "username_already_registered",
+            ErrorList::IncorrectPassword => "incorrect_password",
+            ErrorList::IncorrectUsername => "incorrect_username",
+            ErrorList::InvalidVerificationCode => "invalid_verification_code",
+            ErrorList::Unauthorised => "unauthorised",
+            ErrorList::InsufficientRole(_) => "insufficient_role",
+            ErrorList::NoActiveSeason => "no_active_season",
+            ErrorList::SeasonNotFound => "season_not_found",
+            ErrorList::SeasonAlreadyFinished => "season_already_finished",
+            ErrorList::SeasonNotFinished => "season_not_finished",
+            ErrorList::InvalidSeasonDates => "invalid_season_dates",
+            ErrorList::InvalidMovementPlaces => "invalid_movement_places",
+            ErrorList::MovementsAlreadyApplied => "movements_already_applied",
+            ErrorList::LeagueNotFound => "league_not_found",
+            ErrorList::FixtureNotFound => "fixture_not_found",
+            ErrorList::WinnerNotInFixture => "winner_not_in_fixture",
+            ErrorList::ResultNotCompleted => "result_not_completed",
+            ErrorList::NotLinkedToPlayer => "not_linked_to_player",
+            ErrorList::NotYourFixture => "not_your_fixture",
+            ErrorList::ResultAlreadyConfirmed => "result_already_confirmed",
+            ErrorList::ResultNotPending => "result_not_pending",
+            ErrorList::ResultAlreadySubmitted => "result_already_submitted",
+            ErrorList::OwnResultSubmission => "own_result_submission",
+            ErrorList::ResultNotDisputed => "result_not_disputed",
+            ErrorList::UserNotFound => "user_not_found",
+            ErrorList::PlayerNotFound => "player_not_found",
+            ErrorList::PlayerAlreadyLinked => "player_already_linked",
+            ErrorList::AccountAlreadyLinked => "account_already_linked",
+            ErrorList::InvalidInviteCode => "invalid_invite_code",
+            ErrorList::LinkRequestNotFound => "link_request_not_found",
+            ErrorList::OutboxMessageNotFound => "outbox_message_not_found",
+            ErrorList::AccountLocked(_) => "account_locked",
+            ErrorList::TooManyRequests => "too_many_requests",
+            ErrorList::SessionNotFound => "session_not_found",
+            ErrorList::InvalidCsrfToken => "invalid_csrf_token",
+            ErrorList::ApiTokenNotFound => "api_token_not_found",
+            ErrorList::InvalidApiTokenName => "invalid_api_token_name",
+            ErrorList::InvalidApiTokenExpiry => "invalid_api_token_expiry",
+            ErrorList::TokenScopeDenied(_) => "token_scope_denied",
+            ErrorList::TwoFactorAlreadyEnabled => "two_factor_already_enabled",
+            ErrorList::TwoFactorNotSetUp => "two_factor_not_set_up",
+            ErrorList::TwoFactorNotEnabled => "two_factor_not_enabled",
+            ErrorList::IncorrectTwoFactorCode => "incorrect_two_factor_code",
+            ErrorList::InvalidLoginChallenge => "invalid_login_challenge",
+            ErrorList::TwoFactorRequired => "two_factor_required",
+            ErrorList::MagicLinkDisabled => "magic_link_disabled",
+            ErrorList::InvalidMagicLink => "invalid_magic_link",
+            ErrorList::InvalidLeagueName => "invalid_league_name",
+            ErrorList::InvalidLeagueTier => "invalid_league_tier",
+            ErrorList::LeagueNameTaken => "league_name_taken",
+            ErrorList::LeagueTierTaken => "league_tier_taken",
+            ErrorList::LeagueHasPlayers => "league_has_players",
+            ErrorList::LeagueHasFixtures => "league_has_fixtures",
+            ErrorList::LeagueArchived => "league_archived",
+            ErrorList::InvalidPlayerName => "invalid_player_name",
+            ErrorList::InvalidPhoneNumber => "invalid_phone_number",
+            ErrorList::PlayerHasFixtures => "player_has_fixtures",
+        }
+    }
+
+    // The request field at fault, so forms can highlight it
+    pub fn field(&self) -> Option<&'static str> {
+        match self {
+            ErrorList::InvalidEmail | ErrorList::EmailAlreadyRegistered => Some("email"),
+            ErrorList::InvalidPassword => Some("password"),
+            ErrorList::InvalidUsername | ErrorList::UsernameAlreadyRegistered => Some("username"),
+            ErrorList::NonMatchingPasswords => Some("confirm_password"),
+            ErrorList::InvalidSeasonDates => Some("end_date"),
+            ErrorList::WinnerNotInFixture => Some("winner"),
+            ErrorList::ResultNotCompleted => Some("completed"),
+            ErrorList::InvalidApiTokenName
+            | ErrorList::InvalidLeagueName
+            | ErrorList::LeagueNameTaken
+            | ErrorList::InvalidPlayerName => Some("name"),
+            ErrorList::InvalidPhoneNumber => Some("phone"),
+            ErrorList::LeagueArchived => Some("league_id"),
+            ErrorList::InvalidLeagueTier | ErrorList::LeagueTierTaken => Some("tier"),
+            ErrorList::InvalidApiTokenExpiry => Some("expires_in_days"),
+            _ => None,
+        }
+    }
+}
+
+// The body of every error response
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorResponse {
+    // Stable and machine readable, e.g. "incorrect_password"
+    pub code: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+    // Only set for internal errors, matching the id in the server log
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FieldError {
+    // The request field, e.g. "email" or "sets[1]" for the second set
+    pub field: String,
+    pub message: String,
+}
+
+impl ErrorResponse {
+    pub fn new(code: &str, message: &str) -> Self {
+        Self {
+            code: code.to_string(),
+            message: message.to_string(),
+            fields: vec![],
+            correlation_id: None,
+        }
+    }
+}
+
+// Convert every AppError into its status and a JSON error body. Anything not
+// in ErrorList is unexpected so it's logged and the client just gets an id.
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Internal server error: {}", self.0),
-        )
-            .into_response()
+        if let Some(score_error) = self.0.downcast_ref::<ScoreValidationError>() {
+            let mut body = ErrorResponse::new("invalid_score", &score_error.to_string());
+            body.fields = score_error
+                .errors
+                .iter()
+                .map(|error| FieldError {
+                    field: match error.set {
+                        Some(set) => format!("sets[{}]", set - 1),
+                        None => "sets".to_string(),
+                    },
+                    message: error.reason.clone(),
+                })
+                .collect();
+            return (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response();
+        }
+        if let Some(error) = self.0.downcast_ref::<ErrorList>() {
+            let mut body = ErrorResponse::new(error.code(), &error.to_string());
+            if let Some(field) = error.field() {
+                body.fields.push(FieldError {
+                    field: field.to_string(),
+                    message: error.to_string(),
+                });
+            }
+            return (error.status(), Json(body)).into_response();
+        }
+
+        let correlation_id = generate_unique_id(12);
+        event!(
+            Level::ERROR,
+            correlation_id,
+            "Internal server error: {:?}",
+            self.0
+        );
+        let mut body = ErrorResponse::new(
+            "internal_error",
+            "Something went wrong, please quote the correlation id if you report this",
+        );
+        body.correlation_id = Some(correlation_id);
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(body)).into_response()
     }
 }
 
@@ -112,9 +449,17 @@ pub struct RegistrationDetails {
 
 #[derive(Serialize, Deserialize, Debug, FromRow)]
 pub struct User {
-    username: String,
-    email: String,
+    pub username: String,
+    pub email: String,
     hashed_password: String,
+    #[serde(skip)]
+    locked_until: i64,
+    #[serde(skip)]
+    totp_secret: Option<String>,
+    #[serde(skip)]
+    totp_enabled: bool,
+    #[serde(skip)]
+    totp_last_step: i64,
 }
 
 // Used to extract the user from object from the username header
@@ -168,10 +513,107 @@ impl FromRequestParts<Arc<AppState>> for User {
     }
 }
 
+#[derive(Deserialize)]
+pub struct RoleRequest {
+    pub role: Role,
+}
+
+#[derive(Deserialize)]
+pub struct OutboxQuery {
+    pub status: Option<OutboxStatus>,
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct SessionDetails {
+    #[sqlx(rename = "rowid")]
+    pub session_id: i64,
+    pub created_ts: Option<i64>,
+    pub last_seen_ts: Option<i64>,
+    pub user_agent: Option<String>,
+    // Whether this is the session making the request
+    pub current: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ApiTokenRequest {
+    pub name: String,
+    pub scope: TokenScope,
+    // Tokens without an expiry last until they're revoked
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct ApiTokenDetails {
+    #[sqlx(rename = "rowid")]
+    pub token_id: i64,
+    pub name: String,
+    pub scope: TokenScope,
+    pub created_ts: i64,
+    pub last_used_ts: Option<i64>,
+    pub expiry_ts: Option<i64>,
+}
+
+// Returned once when a token is created, the token itself can't be shown again
+#[derive(Serialize, Deserialize)]
+pub struct NewApiToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub details: ApiTokenDetails,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TwoFactorCode {
+    // A code from the authenticator app or an unused recovery code
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TwoFactorSetup {
+    pub secret: String,
+    // For display as a QR code
+    pub otpauth_uri: String,
+}
+
+// Shown once, only the hashes are kept
+#[derive(Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TwoFactorPolicy {
+    // Whether organisers and super-admins must use two-factor authentication
+    pub required: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MagicLinkRequest {
+    pub email: String,
+}
+
+// Which optional ways of logging in the login page should offer
+#[derive(Serialize, Deserialize)]
+pub struct LoginOptions {
+    pub magic_link_enabled: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MagicLinkLogin {
+    pub code: String,
+}
+
+#[derive(FromRow)]
+struct LoginChallenge {
+    username: String,
+    remember_me: bool,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct LoginDetails {
     email: String,
     password: String,
+    #[serde(default)]
+    remember_me: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -186,106 +628,168 @@ pub struct VerificationDetails {
     code: String,
 }
 
-// pub async fn register(
-//     State(state): State<Arc<AppState>>,
-//     Json(registration_details): Json<RegistrationDetails>,
-// ) -> Result<(StatusCode, HeaderMap, Html<String>), AppError> {
-//     // Validate all the fields
-//     validate_email(&registration_details.email)?;
-//     validate_username(&registration_details.username)?;
-//     validate_password(&registration_details.password)?;
-//     is_unique(
-//         &registration_details.username,
-//         &registration_details.email,
-//         state.clone(),
-//     )
-//     .await?;
-//     if registration_details.password != registration_details.confirm_password {
-//         return Err(ErrorList::NonMatchingPasswords.into());
-//     }
-
-//     event!(
-//         Level::INFO,
-//         "Attempting to create registration for email {} and username {}",
-//         registration_details.email,
-//         registration_details.username
-//     );
-
-//     // Create a registration
-//     sqlx::query("INSERT INTO USERS(email,username,hashed_password) values(?,?,?)")
-//         .bind(&registration_details.email)
-//         .bind(&registration_details.username)
-//         .bind(hash_password(registration_details.password.as_str()))
-//         .execute(&state.db_connection_pool)
-//         .await?;
-
-//     event!(
-//         Level::INFO,
-//         "Attempting to send a verification email to {}",
-//         registration_details.email
-//     );
-
-//     // Send an email
-//     let to = format!(
-//         "{} <{}>",
-//         registration_details.username, registration_details.email
-//     );
-
-//     let code = generate_unique_id(8);
-
-//     let email = Email {
-//         to: to.as_str(),
-//         from: "registration@tld.com",
-//         subject: String::from("Verify your email"),
-//         body: format!(
-//             "<p>Thank you for registering.</p> <p>Please verify for your email using the following code {}.</p>",
-//             code
-//         ),
-//         reply_to: None,
-//     };
-//     add_code(
-//         state.clone(),
-//         &registration_details.email,
-//         &code,
-//         CodeType::EmailVerification,
-//     )
-//     .await?;
-//     send_email(state.clone(), email).await?;
-
-//     let mut header_map = HeaderMap::new();
-//     header_map.insert(
-//         header::LOCATION,
-//         HeaderValue::from_str("/login.html").unwrap(),
-//     );
-
-//     Ok((
-//         StatusCode::OK,
-//         header_map,
-//         Html("Registration successful".to_string()),
-//     ))
-// }
+pub async fn register(
+    State(state): State<Arc<AppState>>,
+    Json(registration_details): Json<RegistrationDetails>,
+) -> Result<(StatusCode, HeaderMap, Html<String>), AppError> {
+    // Validate all the fields
+    validate_email(&registration_details.email)?;
+    validate_username(&registration_details.username)?;
+    validate_password(&registration_details.password)?;
+    is_unique(
+        &registration_details.username,
+        &registration_details.email,
+        state.clone(),
+    )
+    .await?;
+    if registration_details.password != registration_details.confirm_password {
+        return Err(ErrorList::NonMatchingPasswords.into());
+    }
+
+    event!(
+        Level::INFO,
+        "Attempting to create registration for email {} and username {}",
+        registration_details.email,
+        registration_details.username
+    );
+
+    // Create a registration along with its verification email, so there's
+    // never an account that can't be verified
+    let mut transaction = state.db_connection_pool.begin().await?;
+    sqlx::query("INSERT INTO USERS(email,username,hashed_password) values(?,?,?)")
+        .bind(&registration_details.email)
+        .bind(&registration_details.username)
+        .bind(hash_password(registration_details.password.as_str()))
+        .execute(&mut *transaction)
+        .await?;
+
+    event!(
+        Level::INFO,
+        "Attempting to send a verification email to {}",
+        registration_details.email
+    );
+
+    let code = issue_code(
+        &state,
+        &mut transaction,
+        &registration_details.email,
+        CodeType::EmailVerification,
+    )
+    .await?;
+    insert_outbox_message(
+        &mut transaction,
+        &registration_details.email,
+        EmailTemplate::Verification { code },
+    )
+    .await?;
+    transaction.commit().await?;
+
+    let mut header_map = HeaderMap::new();
+    header_map.insert(
+        header::LOCATION,
+        HeaderValue::from_str("/login.html").unwrap(),
+    );
+
+    Ok((
+        StatusCode::OK,
+        header_map,
+        Html("Registration successful".to_string()),
+    ))
+}
+
+// Issues a new code of the given type for the email, returning it to be sent.
+// Only a hash is stored and any earlier code of the same type stops working.
+// Codes other than login links last a day, so no email older than this can
+// still hold a code that works
+pub const CODE_LIFETIME_SECONDS: i64 = 24 * 3600;
+// How far back code_limit_reached counts the codes sent to an account
+pub const CODE_LIMIT_WINDOW_SECONDS: i64 = 3600;
 
 pub async fn add_code(
     state: Arc<AppState>,
-    email: &String,
-    code: &String,
+    email: &str,
+    code_type: CodeType,
+) -> Result<String, anyhow::Error> {
+    let mut transaction = state.db_connection_pool.begin().await?;
+    let code = issue_code(&state, &mut transaction, email, code_type).await?;
+    transaction.commit().await?;
+    Ok(code)
+}
+
+async fn issue_code(
+    state: &AppState,
+    transaction: &mut Transaction<'_, Sqlite>,
+    email: &str,
+    code_type: CodeType,
+) -> Result<String, anyhow::Error> {
+    // Login links are as good as a password so they don't last long
+    let lifetime = match code_type {
+        CodeType::MagicLink => state.config.security.magic_link_minutes * 60,
+        _ => CODE_LIFETIME_SECONDS,
+    };
+    let code_type: String = code_type.into();
+    let code = generate_unique_id(40);
+    let now = Utc::now().timestamp();
+
+    sqlx::query("UPDATE codes SET used=1 WHERE email=? AND code_type=? AND used=0")
+        .bind(email)
+        .bind(&code_type)
+        .execute(&mut **transaction)
+        .await?;
+    sqlx::query("INSERT INTO CODES(code_type,email,code,created_ts,expiry_ts) values(?,?,?,?,?)")
+        .bind(&code_type)
+        .bind(email)
+        .bind(hash_token(&code))
+        .bind(now)
+        .bind(now + lifetime)
+        .execute(&mut **transaction)
+        .await?;
+    Ok(code)
+}
+
+// Marks the code used if it's the current one for this email and type. Codes
+// are generated in upper case but people may type them in any case.
+pub async fn use_code(
+    state: Arc<AppState>,
+    email: &str,
+    code: &str,
     code_type: CodeType,
-) -> Result<(), anyhow::Error> {
-    let _created = sqlx::query(
-        "INSERT INTO CODES(code_type,email,code,created_ts,expiry_ts) values(?,?,?,?,?)",
+) -> Result<bool, anyhow::Error> {
+    // Code timestamps are stored in text columns so compare them as numbers
+    let used = sqlx::query(
+        "UPDATE codes SET used=1
+        WHERE code_type=? AND email=? AND code=? AND used=0 AND CAST(expiry_ts AS INTEGER) > ?",
     )
     .bind(Into::<String>::into(code_type))
     .bind(email)
-    .bind(code)
+    .bind(hash_token(&code.trim().to_ascii_uppercase()))
     .bind(Utc::now().timestamp())
-    .bind(Utc::now().timestamp() + 24 * 3600)
     .execute(&state.db_connection_pool)
     .await?;
-    Ok(())
+    Ok(used.rows_affected() == 1)
+}
+
+// Whether the account has been sent as many codes of this type as it's
+// allowed in the last hour, so the emails can't be used to flood an inbox
+async fn code_limit_reached(
+    state: Arc<AppState>,
+    email: &str,
+    code_type: CodeType,
+) -> Result<bool, anyhow::Error> {
+    let recent: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM codes WHERE email=? AND code_type=? AND CAST(created_ts AS INTEGER) > ?",
+    )
+    .bind(email)
+    .bind(Into::<String>::into(code_type))
+    .bind(Utc::now().timestamp() - CODE_LIMIT_WINDOW_SECONDS)
+    .fetch_one(&state.db_connection_pool)
+    .await?;
+    Ok(recent >= state.config.security.codes_per_hour)
 }
 
 pub async fn login(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Json(login_details): Json<LoginDetails>,
 ) -> Result<(StatusCode, HeaderMap, Html<String>), AppError> {
     let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
@@ -296,77 +800,574 @@ pub async fn login(
         Some(i) => i,
         None => return Err(ErrorList::IncorrectUsername.into()),
     };
+    let now = Utc::now().timestamp();
+    if user.locked_until > now {
+        return Err(ErrorList::AccountLocked(user.locked_until - now).into());
+    }
+
+    if !verify_password(&user.hashed_password, &login_details.password) {
+        record_failed_login(&user, state).await?;
+        return Err(ErrorList::IncorrectPassword.into());
+    }
+
+    // The session isn't started, and failed logins aren't cleared, until the
+    // second factor is checked
+    if user.totp_enabled {
+        let header_map =
+            start_two_factor_challenge(state, &user.username, login_details.remember_me).await?;
+        return Ok((
+            StatusCode::OK,
+            header_map,
+            Html("Two-factor code required".to_string()),
+        ));
+    }
+
+    let header_map =
+        start_session(state, &user.username, login_details.remember_me, &headers).await?;
+    Ok((
+        StatusCode::OK,
+        header_map,
+        Html("Login successful".to_string()),
+    ))
+}
+
+// How long someone has to enter their code after the password is accepted
+const LOGIN_CHALLENGE_SECONDS: i64 = 300;
+const LOGIN_CHALLENGE_ATTEMPTS: i64 = 5;
+
+// Records that the password or login link was accepted, returning the cookie
+// which carries the login on to the second factor step
+async fn start_two_factor_challenge(
+    state: Arc<AppState>,
+    username: &str,
+    remember_me: bool,
+) -> Result<HeaderMap, anyhow::Error> {
+    let challenge = generate_unique_id(60);
+    sqlx::query(
+        "INSERT INTO login_challenges(challenge_hash,username,remember_me,expiry) values(?, ?, ?, ?)",
+    )
+    .bind(hash_token(&challenge))
+    .bind(username)
+    .bind(remember_me)
+    .bind(Utc::now().timestamp() + LOGIN_CHALLENGE_SECONDS)
+    .execute(&state.db_connection_pool)
+    .await?;
+
     let mut header_map = HeaderMap::new();
-    if verify_password(&user.hashed_password, &login_details.password) {
-        let session_key = generate_unique_id(100);
-        let session_cookie = Cookie::build(("session-key", &session_key))
-            .max_age(Duration::days(1000))
-            .http_only(true)
-            .path("/")
-            .build();
-        header_map.insert(
-            header::SET_COOKIE,
-            session_cookie.to_string().parse().unwrap(),
-        );
-        let expiry = Utc::now().timestamp() + (1000 * 24 * 60 * 60);
-        sqlx::query("INSERT INTO sessions(session_key,username, expiry) values(?, ?, ?)")
-            .bind(session_key)
-            .bind(user.username)
-            .bind(expiry)
-            .execute(&state.db_connection_pool)
-            .await?;
-        header_map.insert(
-            header::LOCATION,
-            HeaderValue::from_str("/admin/index.html").unwrap(),
+    header_map.insert(
+        header::SET_COOKIE,
+        challenge_cookie(
+            &state.config.session,
+            &challenge,
+            Some(Duration::seconds(LOGIN_CHALLENGE_SECONDS)),
+        )
+        .to_string()
+        .parse()
+        .unwrap(),
+    );
+    header_map.insert(
+        header::LOCATION,
+        HeaderValue::from_str("/login_two_factor.html").unwrap(),
+    );
+    Ok(header_map)
+}
+
+pub async fn get_login_options(State(state): State<Arc<AppState>>) -> Json<LoginOptions> {
+    Json(LoginOptions {
+        magic_link_enabled: state.config.security.magic_link_enabled,
+    })
+}
+
+// Emails a single use login link. The response is the same whether or not the
+// email is registered so it can't be used to find accounts.
+pub async fn magic_link_initiate(
+    State(state): State<Arc<AppState>>,
+    Json(magic_link_request): Json<MagicLinkRequest>,
+) -> Result<Html<String>, AppError> {
+    if !state.config.security.magic_link_enabled {
+        return Err(ErrorList::MagicLinkDisabled.into());
+    }
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
+        .bind(&magic_link_request.email)
+        .fetch_optional(&state.db_connection_pool)
+        .await?;
+
+    if let Some(user) = user {
+        if code_limit_reached(state.clone(), &user.email, CodeType::MagicLink).await? {
+            event!(
+                Level::WARN,
+                "Not sending another login link to {}",
+                user.email
+            );
+            return Ok(Html(
+                "If that email is registered a login link has been sent".to_string(),
+            ));
+        }
+        let code = add_code(state.clone(), &user.email, CodeType::MagicLink).await?;
+        let link = format!(
+            "{}/magic_link.html?code={}",
+            state.config.server.public_url.trim_end_matches('/'),
+            code
         );
+        queue_email(
+            state.clone(),
+            &user.email,
+            EmailTemplate::MagicLink {
+                link,
+                minutes: state.config.security.magic_link_minutes,
+            },
+        )
+        .await?;
+    }
 
+    Ok(Html(
+        "If that email is registered a login link has been sent".to_string(),
+    ))
+}
+
+// Follows a login link. The link opens a page which posts the code here so
+// mail scanners that fetch links don't use it up.
+pub async fn magic_link_login(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(magic_link_login): Json<MagicLinkLogin>,
+) -> Result<(StatusCode, HeaderMap, Html<String>), AppError> {
+    if !state.config.security.magic_link_enabled {
+        return Err(ErrorList::MagicLinkDisabled.into());
+    }
+    let now = Utc::now().timestamp();
+    let email: Option<String> = sqlx::query_scalar(
+        "UPDATE codes SET used=1
+        WHERE code_type='MagicLink' AND code=? AND used=0 AND CAST(expiry_ts AS INTEGER) > ?
+        RETURNING email",
+    )
+    .bind(hash_token(&magic_link_login.code))
+    .bind(now)
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+    let Some(email) = email else {
+        return Err(ErrorList::InvalidMagicLink.into());
+    };
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
+        .bind(&email)
+        .fetch_optional(&state.db_connection_pool)
+        .await?;
+    let Some(user) = user else {
+        return Err(ErrorList::InvalidMagicLink.into());
+    };
+    if user.locked_until > now {
+        return Err(ErrorList::AccountLocked(user.locked_until - now).into());
+    }
+
+    // The link stands in for the password, not the second factor
+    if user.totp_enabled {
+        let header_map = start_two_factor_challenge(state, &user.username, false).await?;
         return Ok((
             StatusCode::OK,
             header_map,
-            Html("Login successful".to_string()),
+            Html("Two-factor code required".to_string()),
         ));
     }
-    Err(ErrorList::IncorrectPassword.into())
+
+    let header_map = start_session(state, &user.username, false, &headers).await?;
+    Ok((
+        StatusCode::OK,
+        header_map,
+        Html("Login successful".to_string()),
+    ))
 }
 
-pub async fn verify_email(
+// Second step of logging in for accounts with two-factor authentication
+pub async fn login_two_factor(
     State(state): State<Arc<AppState>>,
-    Json(verification_details): Json<VerificationDetails>,
-) -> Result<Html<String>, AppError> {
+    headers: HeaderMap,
+    Json(two_factor_code): Json<TwoFactorCode>,
+) -> Result<(StatusCode, HeaderMap, Html<String>), AppError> {
+    let Some(challenge) = get_cookie(&headers, CHALLENGE_COOKIE) else {
+        return Err(ErrorList::InvalidLoginChallenge.into());
+    };
+    let challenge_hash = hash_token(&challenge);
+    let login_challenge = sqlx::query_as::<_, LoginChallenge>(
+        "UPDATE login_challenges SET attempts=attempts+1
+        WHERE challenge_hash=? AND expiry > ? AND attempts < ?
+        RETURNING username,remember_me",
+    )
+    .bind(&challenge_hash)
+    .bind(Utc::now().timestamp())
+    .bind(LOGIN_CHALLENGE_ATTEMPTS)
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+    let Some(login_challenge) = login_challenge else {
+        return Err(ErrorList::InvalidLoginChallenge.into());
+    };
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE username = ?")
+        .bind(&login_challenge.username)
+        .fetch_one(&state.db_connection_pool)
+        .await?;
     let now = Utc::now().timestamp();
+    if user.locked_until > now {
+        return Err(ErrorList::AccountLocked(user.locked_until - now).into());
+    }
+    // Wrong codes count towards the lockout like wrong passwords, otherwise a
+    // fresh challenge could be started for every few guesses
+    if !check_second_factor(state.clone(), &user, &two_factor_code.code).await? {
+        record_failed_login(&user, state).await?;
+        return Err(ErrorList::IncorrectTwoFactorCode.into());
+    }
+
+    sqlx::query("DELETE FROM login_challenges WHERE challenge_hash=?")
+        .bind(&challenge_hash)
+        .execute(&state.db_connection_pool)
+        .await?;
+    let mut header_map = start_session(
+        state.clone(),
+        &user.username,
+        login_challenge.remember_me,
+        &headers,
+    )
+    .await?;
+    header_map.append(
+        header::SET_COOKIE,
+        challenge_cookie(&state.config.session, "", Some(Duration::ZERO))
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+    Ok((
+        StatusCode::OK,
+        header_map,
+        Html("Login successful".to_string()),
+    ))
+}
 
-    let code_exists = sqlx::query(
-        "SELECT 1 FROM codes WHERE code_type = 'EmailVerification' AND email = ? AND code = ? AND expiry_ts > ?"
+// Creates the session, returning the cookies to set and where to go next. Only
+// called once every factor has been checked so failed logins are cleared here.
+async fn start_session(
+    state: Arc<AppState>,
+    username: &str,
+    remember_me: bool,
+    headers: &HeaderMap,
+) -> Result<HeaderMap, anyhow::Error> {
+    sqlx::query("UPDATE users SET login_attempts=0, lockouts=0, locked_until=0 WHERE username=?")
+        .bind(username)
+        .execute(&state.db_connection_pool)
+        .await?;
+    let now = Utc::now().timestamp();
+    let mut header_map = HeaderMap::new();
+    let session_key = generate_unique_id(100);
+    let csrf_token = generate_unique_id(40);
+    // Ordinary sessions use a browser session cookie so closing the browser ends them
+    let max_age = remember_me.then(|| Duration::days(state.config.session.remember_me_days));
+    header_map.append(
+        header::SET_COOKIE,
+        session_cookie(&state.config.session, &session_key, max_age)
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+    header_map.append(
+        header::SET_COOKIE,
+        csrf_cookie(&state.config.session, &csrf_token, max_age)
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+    let expiry = state.config.session.expiry(remember_me, now, now);
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok());
+    sqlx::query(
+        "INSERT INTO sessions(session_key,username,expiry,created_ts,last_seen_ts,user_agent,remember_me,csrf_token) values(?, ?, ?, ?, ?, ?, ?, ?)",
     )
-    .bind(&verification_details.email)
-    .bind(&verification_details.code)
+    .bind(hash_token(&session_key))
+    .bind(username)
+    .bind(expiry)
     .bind(now)
-    .fetch_optional(&state.db_connection_pool)
+    .bind(now)
+    .bind(user_agent)
+    .bind(remember_me)
+    .bind(csrf_token)
+    .execute(&state.db_connection_pool)
     .await?;
+    header_map.insert(
+        header::LOCATION,
+        HeaderValue::from_str("/admin/index.html").unwrap(),
+    );
+    Ok(header_map)
+}
 
-    if code_exists.is_none() {
-        return Err(ErrorList::InvalidVerificationCode.into());
+// Accepts a current authenticator code or an unused recovery code, using it up
+async fn check_second_factor(
+    state: Arc<AppState>,
+    user: &User,
+    code: &str,
+) -> Result<bool, anyhow::Error> {
+    let now = Utc::now().timestamp();
+    if let Some(step) = user
+        .totp_secret
+        .as_deref()
+        .and_then(|secret| totp::verify(secret, code, now, user.totp_last_step))
+    {
+        // Guards against the same code being used twice at the same moment
+        let updated = sqlx::query(
+            "UPDATE users SET totp_last_step=? WHERE username=? AND totp_last_step < ?",
+        )
+        .bind(step)
+        .bind(&user.username)
+        .bind(step)
+        .execute(&state.db_connection_pool)
+        .await?;
+        return Ok(updated.rows_affected() == 1);
     }
 
-    sqlx::query("UPDATE users SET auth_level = 50 WHERE email = ?")
-        .bind(&verification_details.email)
+    let used =
+        sqlx::query("UPDATE recovery_codes SET used=1 WHERE username=? AND code_hash=? AND used=0")
+            .bind(&user.username)
+            .bind(hash_token(&normalise_recovery_code(code)))
+            .execute(&state.db_connection_pool)
+            .await?;
+    Ok(used.rows_affected() == 1)
+}
+
+fn normalise_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+// Replaces any existing recovery codes with a new set
+async fn generate_recovery_codes(
+    state: Arc<AppState>,
+    username: &str,
+) -> Result<Vec<String>, anyhow::Error> {
+    let mut transaction = state.db_connection_pool.begin().await?;
+    sqlx::query("DELETE FROM recovery_codes WHERE username=?")
+        .bind(username)
+        .execute(&mut *transaction)
+        .await?;
+    let mut recovery_codes = vec![];
+    for _ in 0..10 {
+        let code = generate_unique_id(10);
+        sqlx::query("INSERT INTO recovery_codes(username,code_hash) values(?, ?)")
+            .bind(username)
+            .bind(hash_token(&code))
+            .execute(&mut *transaction)
+            .await?;
+        recovery_codes.push(format!("{}-{}", &code[..5], &code[5..]));
+    }
+    transaction.commit().await?;
+    Ok(recovery_codes)
+}
+
+// Starts enrolment with a new secret, which only takes effect once a code
+// from it has been verified
+pub async fn setup_two_factor(
+    State(state): State<Arc<AppState>>,
+    user: User,
+) -> Result<Json<TwoFactorSetup>, AppError> {
+    if user.totp_enabled {
+        return Err(ErrorList::TwoFactorAlreadyEnabled.into());
+    }
+    let secret = totp::generate_secret();
+    sqlx::query("UPDATE users SET totp_secret=?, totp_last_step=0 WHERE username=?")
+        .bind(&secret)
+        .bind(&user.username)
+        .execute(&state.db_connection_pool)
+        .await?;
+    Ok(Json(TwoFactorSetup {
+        otpauth_uri: totp::otpauth_uri("Tennis Leagues", &user.email, &secret),
+        secret,
+    }))
+}
+
+pub async fn verify_two_factor(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    Json(two_factor_code): Json<TwoFactorCode>,
+) -> Result<Json<RecoveryCodes>, AppError> {
+    if user.totp_enabled {
+        return Err(ErrorList::TwoFactorAlreadyEnabled.into());
+    }
+    let Some(secret) = &user.totp_secret else {
+        return Err(ErrorList::TwoFactorNotSetUp.into());
+    };
+    let Some(step) = totp::verify(
+        secret,
+        &two_factor_code.code,
+        Utc::now().timestamp(),
+        user.totp_last_step,
+    ) else {
+        return Err(ErrorList::IncorrectTwoFactorCode.into());
+    };
+    sqlx::query("UPDATE users SET totp_enabled=1, totp_last_step=? WHERE username=?")
+        .bind(step)
+        .bind(&user.username)
+        .execute(&state.db_connection_pool)
+        .await?;
+    let recovery_codes = generate_recovery_codes(state, &user.username).await?;
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+pub async fn regenerate_recovery_codes(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    Json(two_factor_code): Json<TwoFactorCode>,
+) -> Result<Json<RecoveryCodes>, AppError> {
+    if !user.totp_enabled {
+        return Err(ErrorList::TwoFactorNotEnabled.into());
+    }
+    if !check_second_factor(state.clone(), &user, &two_factor_code.code).await? {
+        return Err(ErrorList::IncorrectTwoFactorCode.into());
+    }
+    let recovery_codes = generate_recovery_codes(state, &user.username).await?;
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+pub async fn disable_two_factor(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    Json(two_factor_code): Json<TwoFactorCode>,
+) -> Result<StatusCode, AppError> {
+    if !user.totp_enabled {
+        return Err(ErrorList::TwoFactorNotEnabled.into());
+    }
+    let auth_level = get_auth_level(&user.username, state.clone()).await?;
+    if auth_level > Role::Player.auth_level() && admin_two_factor_required(state.clone()).await? {
+        return Err(ErrorList::TwoFactorRequired.into());
+    }
+    if !check_second_factor(state.clone(), &user, &two_factor_code.code).await? {
+        return Err(ErrorList::IncorrectTwoFactorCode.into());
+    }
+    sqlx::query(
+        "UPDATE users SET totp_enabled=0, totp_secret=NULL, totp_last_step=0 WHERE username=?",
+    )
+    .bind(&user.username)
+    .execute(&state.db_connection_pool)
+    .await?;
+    sqlx::query("DELETE FROM recovery_codes WHERE username=?")
+        .bind(&user.username)
         .execute(&state.db_connection_pool)
         .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    // Clean up used code
+async fn admin_two_factor_required(state: Arc<AppState>) -> Result<bool, anyhow::Error> {
+    let required: Option<String> =
+        sqlx::query_scalar("SELECT value FROM settings WHERE name='require_admin_two_factor'")
+            .fetch_optional(&state.db_connection_pool)
+            .await?;
+    Ok(required.as_deref() == Some("true"))
+}
+
+pub async fn get_two_factor_policy(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<TwoFactorPolicy>, AppError> {
+    Ok(Json(TwoFactorPolicy {
+        required: admin_two_factor_required(state).await?,
+    }))
+}
+
+pub async fn set_two_factor_policy(
+    State(state): State<Arc<AppState>>,
+    Json(policy): Json<TwoFactorPolicy>,
+) -> Result<StatusCode, AppError> {
     sqlx::query(
-        "UPDATE codes SET used = true WHERE email = ? AND code=? AND code_type='EmailVerification'",
+        "INSERT OR REPLACE INTO settings(name,value) values('require_admin_two_factor', ?)",
     )
-    .bind(&verification_details.email)
-    .bind(&verification_details.code)
+    .bind(policy.required.to_string())
     .execute(&state.db_connection_pool)
     .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Counts a failed login, locking the account once it reaches the threshold.
+// Each lockout in a row lasts twice as long as the one before, up to a cap.
+// The count is kept in the database so guesses sent in parallel all add to it.
+async fn record_failed_login(user: &User, state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let security = &state.config.security;
+    let (login_attempts, lockouts) = sqlx::query_as::<_, (i64, i64)>(
+        "UPDATE users SET login_attempts=login_attempts+1 WHERE username=?
+        RETURNING login_attempts, lockouts",
+    )
+    .bind(&user.username)
+    .fetch_one(&state.db_connection_pool)
+    .await?;
+    if login_attempts < security.max_login_attempts {
+        return Ok(());
+    }
+
+    let lockouts = lockouts + 1;
+    let lockout_seconds = (security.lockout_base_seconds
+        * 2_i64.pow(lockouts.clamp(1, 20) as u32 - 1))
+    .min(security.max_lockout_seconds);
+    // Only one of several requests over the threshold at once locks the account
+    let locked = sqlx::query(
+        "UPDATE users SET login_attempts=0, lockouts=?, locked_until=?
+        WHERE username=? AND login_attempts>=?",
+    )
+    .bind(lockouts)
+    .bind(Utc::now().timestamp() + lockout_seconds)
+    .bind(&user.username)
+    .bind(security.max_login_attempts)
+    .execute(&state.db_connection_pool)
+    .await?;
+    if locked.rows_affected() > 0 {
+        event!(
+            Level::WARN,
+            "Locking account {} for {} seconds after {} failed logins",
+            user.username,
+            lockout_seconds,
+            login_attempts
+        );
+    }
+    Ok(())
+}
+
+pub async fn unlock_user(
+    State(state): State<Arc<AppState>>,
+    Path(username): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let updated = sqlx::query(
+        "UPDATE users SET login_attempts=0, lockouts=0, locked_until=0 WHERE username=?",
+    )
+    .bind(username)
+    .execute(&state.db_connection_pool)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(ErrorList::UserNotFound.into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn verify_email(
+    State(state): State<Arc<AppState>>,
+    Json(verification_details): Json<VerificationDetails>,
+) -> Result<Html<String>, AppError> {
+    if !use_code(
+        state.clone(),
+        &verification_details.email,
+        &verification_details.code,
+        CodeType::EmailVerification,
+    )
+    .await?
+    {
+        return Err(ErrorList::InvalidVerificationCode.into());
+    }
+
+    // Verifying makes the account a player without demoting anyone already above that
+    sqlx::query("UPDATE users SET auth_level = MAX(auth_level, ?) WHERE email = ?")
+        .bind(Role::Player.auth_level())
+        .bind(&verification_details.email)
+        .execute(&state.db_connection_pool)
+        .await?;
 
     Ok(Html("Email successfully verified".to_string()))
 }
 
 pub async fn change_password(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     user: User,
     Json(password_details): Json<ChangePassword>,
 ) -> Result<Html<String>, AppError> {
@@ -384,78 +1385,288 @@ pub async fn change_password(
         .execute(&state.db_connection_pool)
         .await?;
 
+    // Sign out everywhere else in case the old password was compromised
+    sqlx::query("DELETE FROM sessions WHERE username=? AND session_key!=?")
+        .bind(&user.username)
+        .bind(get_session_hash(&headers))
+        .execute(&state.db_connection_pool)
+        .await?;
+
     Ok(Html("Password successfully changed".to_string()))
 }
 
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Html<String>), AppError> {
+    sqlx::query("DELETE FROM sessions WHERE session_key=?")
+        .bind(get_session_hash(&headers))
+        .execute(&state.db_connection_pool)
+        .await?;
+
+    let mut header_map = HeaderMap::new();
+    header_map.append(
+        header::SET_COOKIE,
+        session_cookie(&state.config.session, "", Some(Duration::ZERO))
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+    header_map.append(
+        header::SET_COOKIE,
+        csrf_cookie(&state.config.session, "", Some(Duration::ZERO))
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+    header_map.insert(
+        header::LOCATION,
+        HeaderValue::from_str("/login.html").unwrap(),
+    );
+    Ok((
+        StatusCode::OK,
+        header_map,
+        Html("Logout successful".to_string()),
+    ))
+}
+
+pub async fn get_sessions(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    user: User,
+) -> Result<Json<Vec<SessionDetails>>, AppError> {
+    let mut sessions = sqlx::query_as::<_, SessionDetails>(
+        "SELECT rowid,created_ts,last_seen_ts,user_agent,session_key=? as current
+        FROM sessions WHERE username=? AND expiry > ?
+        ORDER BY last_seen_ts DESC",
+    )
+    .bind(get_session_hash(&headers))
+    .bind(&user.username)
+    .bind(Utc::now().timestamp())
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+    // Show the session making the request first
+    sessions.sort_by_key(|session| !session.current);
+    Ok(Json(sessions))
+}
+
+pub async fn revoke_session(
+    State(state): State<Arc<AppState>>,
+    Path(session_id): Path<i64>,
+    user: User,
+) -> Result<StatusCode, AppError> {
+    let deleted = sqlx::query("DELETE FROM sessions WHERE rowid=? AND username=?")
+        .bind(session_id)
+        .bind(&user.username)
+        .execute(&state.db_connection_pool)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(ErrorList::SessionNotFound.into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Signs out every session except the one making the request
+pub async fn revoke_other_sessions(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    user: User,
+) -> Result<StatusCode, AppError> {
+    sqlx::query("DELETE FROM sessions WHERE username=? AND session_key!=?")
+        .bind(&user.username)
+        .bind(get_session_hash(&headers))
+        .execute(&state.db_connection_pool)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_api_tokens(
+    State(state): State<Arc<AppState>>,
+    user: User,
+) -> Result<Json<Vec<ApiTokenDetails>>, AppError> {
+    let tokens = sqlx::query_as::<_, ApiTokenDetails>(
+        "SELECT rowid,name,scope,created_ts,last_used_ts,expiry_ts FROM api_tokens
+        WHERE username=? ORDER BY created_ts DESC",
+    )
+    .bind(&user.username)
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+    Ok(Json(tokens))
+}
+
+pub async fn create_api_token(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    Json(token_request): Json<ApiTokenRequest>,
+) -> Result<(StatusCode, Json<NewApiToken>), AppError> {
+    let name = token_request.name.trim();
+    if name.is_empty() || name.len() > 100 {
+        return Err(ErrorList::InvalidApiTokenName.into());
+    }
+    let now = Utc::now().timestamp();
+    let expiry_ts = match token_request.expires_in_days {
+        Some(days) if days <= 0 => return Err(ErrorList::InvalidApiTokenExpiry.into()),
+        Some(days) => Some(now + days * 24 * 3600),
+        None => None,
+    };
+
+    let token = format!("tl_{}", generate_unique_id(40));
+    let token_id = sqlx::query(
+        "INSERT INTO api_tokens(token_hash,username,name,scope,created_ts,expiry_ts) values(?, ?, ?, ?, ?, ?)",
+    )
+    .bind(hash_token(&token))
+    .bind(&user.username)
+    .bind(name)
+    .bind(token_request.scope)
+    .bind(now)
+    .bind(expiry_ts)
+    .execute(&state.db_connection_pool)
+    .await?
+    .last_insert_rowid();
+
+    Ok((
+        StatusCode::CREATED,
+        Json(NewApiToken {
+            token,
+            details: ApiTokenDetails {
+                token_id,
+                name: name.to_string(),
+                scope: token_request.scope,
+                created_ts: now,
+                last_used_ts: None,
+                expiry_ts,
+            },
+        }),
+    ))
+}
+
+pub async fn revoke_api_token(
+    State(state): State<Arc<AppState>>,
+    Path(token_id): Path<i64>,
+    user: User,
+) -> Result<StatusCode, AppError> {
+    let deleted = sqlx::query("DELETE FROM api_tokens WHERE rowid=? AND username=?")
+        .bind(token_id)
+        .bind(&user.username)
+        .execute(&state.db_connection_pool)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(ErrorList::ApiTokenNotFound.into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn set_user_role(
+    State(state): State<Arc<AppState>>,
+    Path(username): Path<String>,
+    Json(role_request): Json<RoleRequest>,
+) -> Result<StatusCode, AppError> {
+    let updated = sqlx::query("UPDATE users SET auth_level=? WHERE username=?")
+        .bind(role_request.role.auth_level())
+        .bind(username)
+        .execute(&state.db_connection_pool)
+        .await?;
+    if updated.rows_affected() == 0 {
+        return Err(ErrorList::UserNotFound.into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Outbox messages for admins to check on delivery, optionally filtered by status
+pub async fn get_outbox(
+    State(state): State<Arc<AppState>>,
+    Query(outbox_query): Query<OutboxQuery>,
+) -> Result<Json<Vec<OutboxMessage>>, AppError> {
+    let messages = sqlx::query_as::<_, OutboxMessage>(
+        "SELECT rowid,* FROM outbox WHERE ? IS NULL OR status=? ORDER BY rowid DESC LIMIT 200",
+    )
+    .bind(outbox_query.status)
+    .bind(outbox_query.status)
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+    Ok(Json(messages))
+}
+
+// Puts a failed message back in the queue with a fresh set of attempts
+pub async fn retry_outbox_message(
+    State(state): State<Arc<AppState>>,
+    Path(message_id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    let updated = sqlx::query(
+        "UPDATE outbox SET status='pending', attempts=0, next_attempt_ts=?
+        WHERE rowid=? AND status!='sent' AND text_body!=''",
+    )
+    .bind(Utc::now().timestamp())
+    .bind(message_id)
+    .execute(&state.db_connection_pool)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(ErrorList::OutboxMessageNotFound.into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// The response is the same whether or not the email is registered, or has
+// hit its limit, so it can't be used to find accounts
 pub async fn password_reset_initiate(
     State(state): State<Arc<AppState>>,
     Json(password_reset_request): Json<PasswordResetInitiateRequest>,
 ) -> Result<Html<String>, AppError> {
-    // Check if user exists for provided email
+    let response =
+        Html("If that email is registered a password reset email has been sent".to_string());
     let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
         .bind(&password_reset_request.0)
         .fetch_optional(&state.db_connection_pool)
         .await?;
-
-    let user = match user {
-        Some(u) => u,
-        None => return Err(ErrorList::IncorrectUsername.into()),
+    let Some(user) = user else {
+        return Ok(response);
     };
+    if code_limit_reached(state.clone(), &user.email, CodeType::PasswordReset).await? {
+        event!(
+            Level::WARN,
+            "Not sending another password reset to {}",
+            user.email
+        );
+        return Ok(response);
+    }
 
-    // Generate a code
-    let code = generate_unique_id(8);
-
-    // Add code to database
-    add_code(state.clone(), &user.email, &code, CodeType::PasswordReset).await?;
-
-    // Send email
-    // let _email = Email {
-    //     to: &user.email,
-    //     from: "registration@tld.com",
-    //     subject: String::from("Password Reset"),
-    //     body: format!(
-    //         "<p>A password reset was requested for your account.</p> \
-    //         <p>Use this code to reset your password: {}</p> \
-    //         <p>If you did not request this, please ignore this email.</p>",
-    //         code
-    //     ),
-    //     reply_to: None,
-    // };
-
-    // send_email(state, email).await?;
+    let code = add_code(state.clone(), &user.email, CodeType::PasswordReset).await?;
+    queue_email(state, &user.email, EmailTemplate::PasswordReset { code }).await?;
 
-    Ok(Html("Password reset email sent".to_string()))
+    Ok(response)
 }
 
 pub async fn password_reset_complete(
     State(state): State<Arc<AppState>>,
     Json(password_reset_response): Json<PasswordResetCompleteRequest>,
 ) -> Result<Html<String>, AppError> {
-    // Check if passwords match
+    validate_password(&password_reset_response.password)?;
     if password_reset_response.password != password_reset_response.confirm_password {
         return Err(ErrorList::NonMatchingPasswords.into());
     }
 
-    // Check if code is valid
-    let code = sqlx::query_as::<_,CodeAndEmail>("SELECT code,email FROM codes WHERE code_type='PasswordReset' AND used=0 AND expiry_ts > ? AND code=?")
-            .bind(Utc::now().timestamp())
-                    .bind(password_reset_response.code).fetch_optional(&state.db_connection_pool).await?;
-
-    if let Some(code) = code {
-        // Update password
-        sqlx::query("UPDATE users SET hashed_password=? WHERE email=?")
-            .bind(hash_password(password_reset_response.password.as_str()))
-            .bind(code.1)
-            .execute(&state.db_connection_pool)
-            .await?;
-        // Mark code as used
-        sqlx::query("UPDATE codes SET used=1 WHERE code=?")
-            .bind(code.0)
-            .execute(&state.db_connection_pool)
-            .await?;
-    } else {
+    let email = &password_reset_response.email;
+    if !use_code(
+        state.clone(),
+        email,
+        &password_reset_response.code,
+        CodeType::PasswordReset,
+    )
+    .await?
+    {
         return Err(ErrorList::InvalidVerificationCode.into());
     }
 
+    sqlx::query("UPDATE users SET hashed_password=? WHERE email=?")
+        .bind(hash_password(password_reset_response.password.as_str()))
+        .bind(email)
+        .execute(&state.db_connection_pool)
+        .await?;
+    // Whoever had the old password shouldn't stay signed in
+    sqlx::query("DELETE FROM sessions WHERE username=(SELECT username FROM users WHERE email=?)")
+        .bind(email)
+        .execute(&state.db_connection_pool)
+        .await?;
+
     Ok(Html("Password successfully reset".to_string()))
 }
@@ -1,13 +1,16 @@
 use crate::AppState;
+use lettre::Address;
 use std::sync::Arc;
 
 use super::ErrorList;
 
-pub fn _validate_email(email: &str) -> Result<bool, ErrorList> {
-    if email.contains('@') && email.len() > 3 {
+// The address has to be one mail can be sent to, or queueing the verification
+// email would fail after the account was created
+pub fn validate_email(email: &str) -> Result<bool, ErrorList> {
+    if email.len() > 3 && email.len() < 300 && email.parse::<Address>().is_ok() {
         return Ok(true);
     }
-    Err(ErrorList::_InvalidEmail)
+    Err(ErrorList::InvalidEmail)
 }
 
 pub fn validate_password(password: &str) -> Result<bool, ErrorList> {
@@ -17,14 +20,14 @@ pub fn validate_password(password: &str) -> Result<bool, ErrorList> {
     Err(ErrorList::InvalidPassword)
 }
 
-pub fn _validate_username(username: &str) -> Result<bool, ErrorList> {
+pub fn validate_username(username: &str) -> Result<bool, ErrorList> {
     if username.len() >= 3 && username.len() < 100 {
         return Ok(true);
     }
-    Err(ErrorList::_InvalidUsername)
+    Err(ErrorList::InvalidUsername)
 }
 
-pub async fn _is_unique(
+pub async fn is_unique(
     username: &String,
     email: &String,
     state: Arc<AppState>,
@@ -36,7 +39,7 @@ pub async fn _is_unique(
 
     if let Ok(user) = username {
         if user.is_some() {
-            return Err(ErrorList::_UsernameAlreadyRegistered);
+            return Err(ErrorList::UsernameAlreadyRegistered);
         }
     }
 
@@ -47,7 +50,7 @@ pub async fn _is_unique(
 
     if let Ok(email) = email {
         if email.is_some() {
-            return Err(ErrorList::_EmailAlreadyRegistered);
+            return Err(ErrorList::EmailAlreadyRegistered);
         }
     }
     Ok(true)
@@ -0,0 +1,293 @@
+use anyhow::anyhow;
+use chrono::Utc;
+use lettre::{
+    message::{Mailbox, MultiPart},
+    FileTransport, Message, SmtpTransport, Transport,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Sqlite, Transaction};
+use std::{sync::Arc, time::Duration};
+use tracing::{event, Level};
+
+use crate::AppState;
+
+// Where outgoing mail goes, chosen by the email.transport config setting
+#[derive(Clone)]
+pub enum Mailer {
+    Smtp(SmtpTransport),
+    // Writes each message to a .eml file so mail can be checked without a relay
+    File(FileTransport),
+}
+
+impl Mailer {
+    pub async fn send(&self, message: Message) -> Result<(), anyhow::Error> {
+        // Both transports block so keep them off the async runtime
+        let mailer = self.clone();
+        tokio::task::spawn_blocking(move || match mailer {
+            Mailer::Smtp(transport) => transport.send(&message).map(|_| ()).map_err(|e| anyhow!(e)),
+            Mailer::File(transport) => transport.send(&message).map(|_| ()).map_err(|e| anyhow!(e)),
+        })
+        .await?
+    }
+}
+
+pub enum EmailTemplate {
+    Verification { code: String },
+    PasswordReset { code: String },
+    FixtureReminder { opponent: String, deadline: String },
+    ResultConfirmation { opponent: String, score: String },
+    MagicLink { link: String, minutes: i64 },
+}
+
+pub struct RenderedEmail {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+impl EmailTemplate {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EmailTemplate::Verification { .. } => "verification",
+            EmailTemplate::PasswordReset { .. } => "password_reset",
+            EmailTemplate::FixtureReminder { .. } => "fixture_reminder",
+            EmailTemplate::ResultConfirmation { .. } => "result_confirmation",
+            EmailTemplate::MagicLink { .. } => "magic_link",
+        }
+    }
+
+    pub fn render(&self) -> RenderedEmail {
+        match self {
+            EmailTemplate::Verification { code } => RenderedEmail {
+                subject: "Verify your email".to_string(),
+                text: format!(
+                    "Thank you for registering.\n\nPlease verify your email using the following code: {}",
+                    code
+                ),
+                html: format!(
+                    "<p>Thank you for registering.</p><p>Please verify your email using the following code <b>{}</b>.</p>",
+                    escape_html(code)
+                ),
+            },
+            EmailTemplate::PasswordReset { code } => RenderedEmail {
+                subject: "Password Reset".to_string(),
+                text: format!(
+                    "A password reset was requested for your account.\n\nUse this code to reset your password: {}\n\nIf you did not request this, please ignore this email.",
+                    code
+                ),
+                html: format!(
+                    "<p>A password reset was requested for your account.</p><p>Use this code to reset your password: <b>{}</b></p><p>If you did not request this, please ignore this email.</p>",
+                    escape_html(code)
+                ),
+            },
+            EmailTemplate::FixtureReminder { opponent, deadline } => RenderedEmail {
+                subject: format!("Reminder: your match against {}", opponent),
+                text: format!(
+                    "Your match against {} needs to be played by {}.\n\nPlease arrange it with your opponent and submit the result once it's done.",
+                    opponent, deadline
+                ),
+                html: format!(
+                    "<p>Your match against <b>{}</b> needs to be played by <b>{}</b>.</p><p>Please arrange it with your opponent and submit the result once it's done.</p>",
+                    escape_html(opponent),
+                    escape_html(deadline)
+                ),
+            },
+            EmailTemplate::ResultConfirmation { opponent, score } => RenderedEmail {
+                subject: format!("Please confirm your result against {}", opponent),
+                text: format!(
+                    "{} has submitted the result of your match as {}.\n\nPlease log in to confirm or dispute it.",
+                    opponent, score
+                ),
+                html: format!(
+                    "<p><b>{}</b> has submitted the result of your match as <b>{}</b>.</p><p>Please log in to confirm or dispute it.</p>",
+                    escape_html(opponent),
+                    escape_html(score)
+                ),
+            },
+            EmailTemplate::MagicLink { link, minutes } => RenderedEmail {
+                subject: "Your login link".to_string(),
+                text: format!(
+                    "Use this link to log in: {}\n\nIt works once and expires in {} minutes. If you did not request this, please ignore this email.",
+                    link, minutes
+                ),
+                html: format!(
+                    "<p><a href=\"{}\">Log in to Tennis Leagues</a></p><p>The link works once and expires in {} minutes. If you did not request this, please ignore this email.</p>",
+                    escape_html(link),
+                    minutes
+                ),
+            },
+        }
+    }
+}
+
+// Adds the rendered template to the outbox for the worker to deliver, so
+// handlers never wait on the relay and nothing is lost if it's down
+pub async fn queue_email(
+    state: Arc<AppState>,
+    to: &str,
+    template: EmailTemplate,
+) -> Result<(), anyhow::Error> {
+    let mut transaction = state.db_connection_pool.begin().await?;
+    insert_outbox_message(&mut transaction, to, template).await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+// Queues the email as part of a larger change, so it's only sent if the rest
+// of the change is saved
+pub async fn insert_outbox_message(
+    transaction: &mut Transaction<'_, Sqlite>,
+    to: &str,
+    template: EmailTemplate,
+) -> Result<(), anyhow::Error> {
+    // Catch bad addresses now rather than when the worker picks them up
+    to.parse::<Mailbox>()?;
+    event!(Level::INFO, "Queueing {} email to {}", template.name(), to);
+    let rendered = template.render();
+    let now = Utc::now().timestamp();
+    sqlx::query(
+        "INSERT INTO outbox(recipient,template,subject,text_body,html_body,next_attempt_ts,created_ts)
+        values(?,?,?,?,?,?,?)",
+    )
+    .bind(to)
+    .bind(template.name())
+    .bind(rendered.subject)
+    .bind(rendered.text)
+    .bind(rendered.html)
+    .bind(now)
+    .bind(now)
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum OutboxStatus {
+    Pending,
+    Sent,
+    // Gave up after the maximum number of attempts
+    Dead,
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct OutboxMessage {
+    #[sqlx(rename = "rowid")]
+    pub message_id: i64,
+    pub recipient: String,
+    pub template: String,
+    pub subject: String,
+    #[serde(skip)]
+    pub text_body: String,
+    #[serde(skip)]
+    pub html_body: String,
+    pub status: OutboxStatus,
+    pub attempts: i64,
+    pub next_attempt_ts: i64,
+    pub last_error: Option<String>,
+    pub created_ts: i64,
+    pub sent_ts: Option<i64>,
+}
+
+// Doubles the wait after each failed attempt
+pub fn retry_delay(attempts: i64, base_seconds: i64) -> i64 {
+    base_seconds * 2_i64.pow(attempts.saturating_sub(1).clamp(0, 20) as u32)
+}
+
+// Runs forever, delivering due outbox messages every poll interval
+pub async fn run_outbox_worker(state: Arc<AppState>) {
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(state.config.email.outbox_poll_seconds));
+    loop {
+        interval.tick().await;
+        if let Err(e) = deliver_due_messages(state.clone()).await {
+            event!(Level::ERROR, "Outbox worker failed: {}", e);
+        }
+    }
+}
+
+async fn deliver_due_messages(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let messages = sqlx::query_as::<_, OutboxMessage>(
+        "SELECT rowid,* FROM outbox WHERE status='pending' AND next_attempt_ts <= ?
+        ORDER BY next_attempt_ts LIMIT 20",
+    )
+    .bind(Utc::now().timestamp())
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+
+    for message in messages {
+        match deliver(&message, state.clone()).await {
+            Ok(()) => {
+                // Bodies can hold login links and codes so they aren't kept once sent
+                sqlx::query(
+                    "UPDATE outbox SET status='sent', attempts=attempts+1, sent_ts=?, last_error=NULL,
+                    text_body='', html_body='' WHERE rowid=?",
+                )
+                .bind(Utc::now().timestamp())
+                .bind(message.message_id)
+                .execute(&state.db_connection_pool)
+                .await?;
+            }
+            Err(e) => {
+                let attempts = message.attempts + 1;
+                let email_config = &state.config.email;
+                let status = if attempts >= email_config.max_attempts {
+                    event!(
+                        Level::ERROR,
+                        "Giving up on email {} to {} after {} attempts: {}",
+                        message.message_id,
+                        message.recipient,
+                        attempts,
+                        e
+                    );
+                    OutboxStatus::Dead
+                } else {
+                    event!(
+                        Level::WARN,
+                        "Failed to send email {} to {}, will retry: {}",
+                        message.message_id,
+                        message.recipient,
+                        e
+                    );
+                    OutboxStatus::Pending
+                };
+                sqlx::query(
+                    "UPDATE outbox SET status=?, attempts=?, next_attempt_ts=?, last_error=? WHERE rowid=?",
+                )
+                .bind(status)
+                .bind(attempts)
+                .bind(
+                    Utc::now().timestamp()
+                        + retry_delay(attempts, email_config.retry_base_seconds),
+                )
+                .bind(e.to_string())
+                .bind(message.message_id)
+                .execute(&state.db_connection_pool)
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Sends as a plain text and HTML alternative from the configured address
+async fn deliver(message: &OutboxMessage, state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let email = Message::builder()
+        .from(state.config.email.from.parse::<Mailbox>()?)
+        .to(message.recipient.parse::<Mailbox>()?)
+        .subject(message.subject.clone())
+        .multipart(MultiPart::alternative_plain_html(
+            message.text_body.clone(),
+            message.html_body.clone(),
+        ))?;
+    state.email_connection_pool.send(email).await
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -2,10 +2,10 @@
 
 use axum::Router;
 use config::AppState;
-use middleware::ValidateSessionLayer;
+use middleware::{JsonErrorsLayer, ValidateSessionLayer};
 use routes::*;
 use sqlx::migrate;
-use std::{sync::Arc, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tower::ServiceBuilder;
 use tower_http::{
     services::{ServeDir, ServeFile},
@@ -17,8 +17,10 @@ mod app_route_handlers;
 mod auth;
 mod config;
 mod default_route_handlers;
+mod email;
 mod middleware;
 mod routes;
+mod totp;
 mod utilities;
 
 #[cfg(test)]
@@ -41,18 +43,30 @@ async fn main() {
         .await
         .expect("Couldn't complete migrations");
 
+    event!(Level::INFO, "Starting email outbox worker");
+    tokio::spawn(email::run_outbox_worker(app_state.clone()));
+
+    event!(Level::INFO, "Starting session cleanup task");
+    tokio::spawn(auth::run_session_cleanup(app_state.clone()));
+
     let app = get_app(app_state.clone());
 
     let listener = tokio::net::TcpListener::bind(("127.0.0.1", app_state.config.server.port))
         .await
         .unwrap();
-    axum::serve(listener, app).await.unwrap();
+    // Connect info gives the rate limiter each client's address
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
 
 pub fn get_app(state: Arc<AppState>) -> Router {
     let assets = ServeDir::new("assets").not_found_service(ServeFile::new("assets/404.html"));
-    let protected_routes = get_protected_routes();
-    let open_routes = get_open_routes();
+    let protected_routes = get_protected_routes(state.clone());
+    let open_routes = get_open_routes(state.clone());
 
     Router::new()
         .merge(protected_routes)
@@ -60,10 +74,13 @@ pub fn get_app(state: Arc<AppState>) -> Router {
         .merge(open_routes)
         .with_state(state.clone())
         .nest_service("/", assets)
+        // Outermost so responses from the timeout get a JSON body too
         .layer(
-            ServiceBuilder::new().layer(TimeoutLayer::new(Duration::from_secs(
-                state.config.server.request_timeout,
-            ))),
+            ServiceBuilder::new()
+                .layer(JsonErrorsLayer)
+                .layer(TimeoutLayer::new(Duration::from_secs(
+                    state.config.server.request_timeout,
+                ))),
         )
 }
 
@@ -1,14 +1,26 @@
 use axum::{
-    extract::Request,
+    extract::{ConnectInfo, Request},
     response::{IntoResponse, Response},
+    Json,
 };
+use cookie::time::Duration as CookieDuration;
 use futures_util::future::BoxFuture;
 use http::{header, HeaderMap, HeaderValue};
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use tower::{Layer, Service};
 
-use crate::{auth::validate_cookie, AppState};
+use crate::{
+    auth::{
+        check_csrf, csrf_cookie, get_auth_level, get_bearer_token, get_cookie, needs_two_factor,
+        session_cookie, validate_api_token, validate_cookie, Role, SESSION_COOKIE,
+    },
+    default_route_handlers::{AppError, ErrorList, ErrorResponse},
+    AppState,
+};
 
 #[derive(Clone)]
 pub struct ValidateSessionLayer {
@@ -57,22 +69,375 @@ where
         let state = self.state.clone();
 
         Box::pin(async move {
-            let response: Response;
-            if let Ok(username) = validate_cookie(request.headers(), state).await {
+            // Scripts authenticate with an API token instead of a cookie. They
+            // can't be tricked into sending it cross-site so skip the CSRF check.
+            if let Some(token) = get_bearer_token(request.headers()) {
+                let Ok(session) = validate_api_token(&token, state.clone()).await else {
+                    return Ok(AppError::from(ErrorList::Unauthorised).into_response());
+                };
+                if !session.scope.allows(request.method(), request.uri().path()) {
+                    return Ok(
+                        AppError::from(ErrorList::TokenScopeDenied(session.scope)).into_response()
+                    );
+                }
                 request.headers_mut().insert(
                     "username",
-                    HeaderValue::from_str(username.0.as_str()).unwrap(),
+                    HeaderValue::from_str(session.username.as_str()).unwrap(),
+                );
+                return inner.call(request).await;
+            }
+
+            let mut response: Response;
+            let session_key = get_cookie(request.headers(), SESSION_COOKIE);
+            if let Ok(session) = validate_cookie(request.headers(), state.clone()).await {
+                if let Err(e) = check_csrf(request.method(), request.headers(), &session) {
+                    return Ok(AppError::from(e).into_response());
+                }
+                request.headers_mut().insert(
+                    "username",
+                    HeaderValue::from_str(session.username.as_str()).unwrap(),
                 );
 
                 let future = inner.call(request);
                 response = future.await?;
+                // Keep the browser's copy of a remember me cookie in step with
+                // the renewed session, unless the handler set its own cookie
+                if let (true, Some(session_key)) = (session.remember_me, session_key) {
+                    if !response.headers().contains_key(header::SET_COOKIE) {
+                        let config = &state.config.session;
+                        let max_age = Some(CookieDuration::days(config.remember_me_days));
+                        let mut cookies = vec![session_cookie(config, &session_key, max_age)];
+                        if let Some(csrf_token) = &session.csrf_token {
+                            cookies.push(csrf_cookie(config, csrf_token, max_age));
+                        }
+                        for cookie in cookies {
+                            response.headers_mut().append(
+                                header::SET_COOKIE,
+                                HeaderValue::from_str(&cookie.to_string()).unwrap(),
+                            );
+                        }
+                    }
+                }
             } else {
                 let mut headers = HeaderMap::new();
                 headers.insert(
                     header::LOCATION,
                     HeaderValue::from_str("/login.html").unwrap(),
                 );
-                response = (headers, http::StatusCode::UNAUTHORIZED).into_response();
+                response = (headers, AppError::from(ErrorList::Unauthorised)).into_response();
+            }
+            Ok(response)
+        })
+    }
+}
+
+// Rejects requests from users below the given role with a 403. Must sit inside
+// ValidateSessionLayer as it relies on the username header set there.
+#[derive(Clone)]
+pub struct RequireRoleLayer {
+    pub state: Arc<AppState>,
+    pub role: Role,
+}
+
+impl RequireRoleLayer {
+    pub fn new(state: Arc<AppState>, role: Role) -> Self {
+        Self { state, role }
+    }
+}
+
+impl<S> Layer<S> for RequireRoleLayer {
+    type Service = RequireRole<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireRole {
+            inner,
+            state: self.state.clone(),
+            role: self.role,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequireRole<S> {
+    pub inner: S,
+    pub state: Arc<AppState>,
+    pub role: Role,
+}
+
+impl<S> Service<Request> for RequireRole<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static + Clone,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let state = self.state.clone();
+        let role = self.role;
+
+        Box::pin(async move {
+            let username = request
+                .headers()
+                .get("username")
+                .and_then(|username| username.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            let auth_level = match get_auth_level(&username, state.clone()).await {
+                Ok(auth_level) => auth_level,
+                Err(e) => return Ok(AppError::from(e).into_response()),
+            };
+            if auth_level < role.auth_level() {
+                return Ok(AppError::from(ErrorList::InsufficientRole(role)).into_response());
+            }
+            // Captains and admins without a second factor can still reach
+            // their account routes to set one up, just not the routes above
+            // player level, which include those that change results
+            if role.auth_level() > Role::Player.auth_level() {
+                match needs_two_factor(&username, state).await {
+                    Ok(false) => {}
+                    Ok(true) => {
+                        return Ok(AppError::from(ErrorList::TwoFactorRequired).into_response())
+                    }
+                    Err(e) => return Ok(AppError::from(e).into_response()),
+                }
+            }
+            inner.call(request).await
+        })
+    }
+}
+
+// Recent request times for each client IP
+type RequestLog = Arc<Mutex<HashMap<IpAddr, VecDeque<Instant>>>>;
+
+// Allows each IP a number of requests per sliding window and answers the rest
+// with a 429. Needs the app served with connect info to see client addresses.
+// Requests from a trusted proxy are counted against the client it forwarded.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    pub max_requests: usize,
+    pub window: Duration,
+    pub trusted_proxies: Arc<Vec<IpAddr>>,
+    requests: RequestLog,
+}
+
+impl RateLimitLayer {
+    pub fn new(max_requests: usize, window: Duration, trusted_proxies: Vec<IpAddr>) -> Self {
+        Self {
+            max_requests,
+            window,
+            trusted_proxies: Arc::new(trusted_proxies),
+            requests: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            max_requests: self.max_requests,
+            window: self.window,
+            trusted_proxies: self.trusted_proxies.clone(),
+            requests: self.requests.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    pub inner: S,
+    pub max_requests: usize,
+    pub window: Duration,
+    pub trusted_proxies: Arc<Vec<IpAddr>>,
+    requests: RequestLog,
+}
+
+impl<S> RateLimit<S> {
+    fn allow(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+        // Forget anyone who has gone quiet so the map doesn't grow forever
+        requests.retain(|_, times| {
+            times
+                .back()
+                .is_some_and(|last| now.duration_since(*last) < self.window)
+        });
+        let times = requests.entry(ip).or_default();
+        while times
+            .front()
+            .is_some_and(|first| now.duration_since(*first) >= self.window)
+        {
+            times.pop_front();
+        }
+        if times.len() >= self.max_requests {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+impl<S> Service<Request> for RateLimit<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static + Clone,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // Without connect info every client shares one allowance
+        let peer = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |info| info.0.ip());
+        let ip = client_ip(request.headers(), peer, &self.trusted_proxies);
+        let allowed = self.allow(ip);
+        let mut inner = self.inner.clone();
+
+        Box::pin(async move {
+            if !allowed {
+                return Ok(AppError::from(ErrorList::TooManyRequests).into_response());
+            }
+            inner.call(request).await
+        })
+    }
+}
+
+// The address a request came from. Forwarding headers are only believed when
+// the connection is from a trusted proxy, and then the client is the last
+// address added before reaching one, as anything earlier can be made up.
+pub fn client_ip(headers: &HeaderMap, peer: IpAddr, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer) {
+        return peer;
+    }
+    let mut forwarded: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(parse_forwarded_address)
+        .collect();
+    if forwarded.is_empty() {
+        // The standard header looks like: for=192.0.2.1;proto=https, for="[2001:db8::1]:80"
+        forwarded = headers
+            .get_all(header::FORWARDED)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split([',', ';']))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .filter(|(name, _)| name.eq_ignore_ascii_case("for"))
+            .filter_map(|(_, address)| parse_forwarded_address(address))
+            .collect();
+    }
+    forwarded
+        .iter()
+        .rev()
+        .find(|address| !trusted_proxies.contains(address))
+        .or(forwarded.first())
+        .copied()
+        .unwrap_or(peer)
+}
+
+// Accepts a bare address or one with a port, optionally quoted
+fn parse_forwarded_address(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|address| address.ip()))
+        .or_else(|| {
+            value
+                .strip_prefix('[')
+                .and_then(|value| value.strip_suffix(']'))
+                .and_then(|value| value.parse().ok())
+        })
+}
+
+// Gives the plain text rejections from axum's extractors, such as a body that
+// isn't valid JSON, and empty error responses like timeouts the same JSON
+// error body as everything else
+#[derive(Clone)]
+pub struct JsonErrorsLayer;
+
+impl<S> Layer<S> for JsonErrorsLayer {
+    type Service = JsonErrors<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        JsonErrors { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct JsonErrors<S> {
+    pub inner: S,
+}
+
+impl<S> Service<Request> for JsonErrors<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static + Clone,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let mut inner = self.inner.clone();
+
+        Box::pin(async move {
+            let response = inner.call(request).await?;
+            let status = response.status();
+            // Responses with no content type, such as timeouts, have no body
+            let is_text = response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .map(|content_type| content_type.to_str().unwrap_or_default())
+                .is_none_or(|content_type| content_type.starts_with("text/plain"));
+            if !is_text || !(status.is_client_error() || status.is_server_error()) {
+                return Ok(response);
+            }
+
+            let (parts, body) = response.into_parts();
+            let message = axum::body::to_bytes(body, 64 * 1024)
+                .await
+                .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                .unwrap_or_default();
+            let message = match message.is_empty() {
+                true => status.canonical_reason().unwrap_or_default().to_string(),
+                false => message,
+            };
+            if status.is_server_error() {
+                return Ok(AppError::from(anyhow::anyhow!(message)).into_response());
+            }
+            let code = status
+                .canonical_reason()
+                .unwrap_or("invalid request")
+                .to_lowercase()
+                .replace(' ', "_");
+            let mut response = (status, Json(ErrorResponse::new(&code, &message))).into_response();
+            for (name, value) in parts.headers.iter() {
+                if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+                    response.headers_mut().insert(name, value.clone());
+                }
             }
             Ok(response)
         })
@@ -1,11 +1,36 @@
-use crate::{app_route_handlers, default_route_handlers, AppState};
+use crate::{
+    app_route_handlers,
+    auth::Role,
+    default_route_handlers,
+    middleware::{RateLimitLayer, RequireRoleLayer},
+    AppState,
+};
 use axum::{
-    routing::{get, patch, post, put},
+    routing::{delete, get, patch, post, put},
     Router,
 };
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
+
+// Routes needing a session, grouped by the minimum role each group requires
+pub fn get_protected_routes(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .merge(get_account_routes())
+        .merge(get_player_routes().route_layer(RequireRoleLayer::new(state.clone(), Role::Player)))
+        .merge(
+            get_captain_routes().route_layer(RequireRoleLayer::new(state.clone(), Role::Captain)),
+        )
+        .merge(
+            get_organiser_routes()
+                .route_layer(RequireRoleLayer::new(state.clone(), Role::Organiser)),
+        )
+        .merge(
+            get_super_admin_routes()
+                .route_layer(RequireRoleLayer::new(state.clone(), Role::SuperAdmin)),
+        )
+}
 
-pub fn get_protected_routes() -> Router<Arc<AppState>> {
+// Available to any logged in user, including those yet to verify their email
+fn get_account_routes() -> Router<Arc<AppState>> {
     Router::new()
         .route(
             "/account/verifyEmail",
@@ -15,37 +40,216 @@ pub fn get_protected_routes() -> Router<Arc<AppState>> {
             "/account/changePassword",
             patch(default_route_handlers::change_password),
         )
+        .route("/account/logout", post(default_route_handlers::logout))
+        .route(
+            "/account/sessions",
+            get(default_route_handlers::get_sessions)
+                .delete(default_route_handlers::revoke_other_sessions),
+        )
+        .route(
+            "/account/sessions/:session_id",
+            delete(default_route_handlers::revoke_session),
+        )
+        .route(
+            "/account/apiTokens",
+            get(default_route_handlers::get_api_tokens)
+                .post(default_route_handlers::create_api_token),
+        )
+        .route(
+            "/account/apiTokens/:token_id",
+            delete(default_route_handlers::revoke_api_token),
+        )
+        .route(
+            "/account/twoFactor",
+            delete(default_route_handlers::disable_two_factor),
+        )
+        .route(
+            "/account/twoFactor/setup",
+            post(default_route_handlers::setup_two_factor),
+        )
+        .route(
+            "/account/twoFactor/verify",
+            post(default_route_handlers::verify_two_factor),
+        )
+        .route(
+            "/account/twoFactor/recoveryCodes",
+            post(default_route_handlers::regenerate_recovery_codes),
+        )
+}
+
+fn get_player_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/api/myResult", put(app_route_handlers::submit_own_result))
+        .route(
+            "/api/result/:fixture_id/confirm",
+            patch(app_route_handlers::confirm_result),
+        )
+        .route(
+            "/api/result/:fixture_id/dispute",
+            patch(app_route_handlers::dispute_result),
+        )
+        .route("/api/me", get(app_route_handlers::get_me))
+        .route(
+            "/api/me/claimInvite",
+            post(app_route_handlers::claim_player_invite),
+        )
+        .route(
+            "/api/me/linkRequest",
+            post(app_route_handlers::request_player_link),
+        )
+}
+
+fn get_captain_routes() -> Router<Arc<AppState>> {
+    Router::new().route(
+        "/api/admin/leagueTable/:league_id",
+        get(app_route_handlers::generate_league_table),
+    )
+}
+
+fn get_organiser_routes() -> Router<Arc<AppState>> {
+    Router::new()
         .route(
             "/api/allFixtures",
-            get(app_route_handlers::generate_fixtures),
+            post(app_route_handlers::generate_fixtures),
         )
         .route("/api/result", put(app_route_handlers::put_result))
         .route("/api/player", post(app_route_handlers::create_player))
+        .route("/api/players", get(app_route_handlers::get_players))
+        .route(
+            "/api/player/:player_id",
+            get(app_route_handlers::get_player)
+                .patch(app_route_handlers::update_player)
+                .delete(app_route_handlers::delete_player),
+        )
         .route("/api/league", post(app_route_handlers::create_league))
+        .route(
+            "/api/league/:league_id",
+            patch(app_route_handlers::update_league).delete(app_route_handlers::delete_league),
+        )
+        .route(
+            "/api/league/:league_id/archive",
+            patch(app_route_handlers::archive_league),
+        )
         .route(
             "/api/player",
             patch(app_route_handlers::add_player_to_league),
         )
         .route(
-            "/api/admin/leagueTable/:league_id",
-            get(app_route_handlers::generate_league_table),
+            "/api/fixtureReminders",
+            post(app_route_handlers::send_fixture_reminders),
+        )
+        .route(
+            "/api/admin/disputedResults",
+            get(app_route_handlers::get_disputed_results),
+        )
+        .route(
+            "/api/admin/result/:fixture_id",
+            patch(app_route_handlers::resolve_disputed_result),
+        )
+        .route(
+            "/api/player/:player_id/user",
+            patch(app_route_handlers::link_player_to_user),
+        )
+        .route(
+            "/api/player/:player_id/invite",
+            post(app_route_handlers::create_player_invite),
+        )
+        .route(
+            "/api/admin/user/:username/unlock",
+            post(default_route_handlers::unlock_user),
+        )
+        .route(
+            "/api/admin/linkRequests",
+            get(app_route_handlers::get_link_requests),
+        )
+        .route(
+            "/api/admin/linkRequest/:request_id",
+            patch(app_route_handlers::decide_link_request),
+        )
+        .route("/api/season", post(app_route_handlers::create_season))
+        .route(
+            "/api/activeSeason",
+            patch(app_route_handlers::activate_season),
+        )
+        .route(
+            "/api/league/:league_id/format",
+            patch(app_route_handlers::set_league_format),
+        )
+        .route(
+            "/api/league/:league_id/scoring",
+            patch(app_route_handlers::set_league_scoring),
+        )
+        .route(
+            "/api/league/:league_id/movement",
+            patch(app_route_handlers::set_league_movement),
+        )
+        .route(
+            "/api/promotionRelegation/:season_id",
+            get(app_route_handlers::preview_promotion_relegation)
+                .post(app_route_handlers::apply_promotion_relegation),
         )
 }
 
-pub fn get_open_routes() -> Router<Arc<AppState>> {
+fn get_super_admin_routes() -> Router<Arc<AppState>> {
     Router::new()
-        .route("/account/login", post(default_route_handlers::login))
         .route(
-            "/account/resetPassword",
-            post(default_route_handlers::password_reset_initiate),
+            "/api/admin/user/:username/role",
+            patch(default_route_handlers::set_user_role),
         )
+        .route("/api/admin/outbox", get(default_route_handlers::get_outbox))
         .route(
-            "/account/resetPassword",
-            patch(default_route_handlers::password_reset_complete),
+            "/api/admin/outbox/:message_id/retry",
+            post(default_route_handlers::retry_outbox_message),
+        )
+        .route(
+            "/api/admin/twoFactorPolicy",
+            get(default_route_handlers::get_two_factor_policy)
+                .patch(default_route_handlers::set_two_factor_policy),
+        )
+}
+pub fn get_open_routes(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .merge(get_throttled_routes().route_layer(RateLimitLayer::new(
+            state.config.security.rate_limit_requests,
+            Duration::from_secs(state.config.security.rate_limit_window_seconds),
+            state.config.security.trusted_proxies.clone(),
+        )))
+        .route(
+            "/account/loginOptions",
+            get(default_route_handlers::get_login_options),
         )
         .route(
             "/api/leagueTable/:league_id",
             get(app_route_handlers::generate_league_table),
         )
         .route("/api/leagues", get(app_route_handlers::get_leagues))
+        .route(
+            "/api/league/:league_id",
+            get(app_route_handlers::get_league),
+        )
+        .route("/api/seasons", get(app_route_handlers::get_seasons))
+}
+
+// Routes open to password guessing or email flooding, limited per client IP
+fn get_throttled_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/account/register", post(default_route_handlers::register))
+        .route("/account/login", post(default_route_handlers::login))
+        .route(
+            "/account/login/twoFactor",
+            post(default_route_handlers::login_two_factor),
+        )
+        .route(
+            "/account/magicLink",
+            post(default_route_handlers::magic_link_initiate),
+        )
+        .route(
+            "/account/magicLink/login",
+            post(default_route_handlers::magic_link_login),
+        )
+        .route(
+            "/account/resetPassword",
+            post(default_route_handlers::password_reset_initiate)
+                .patch(default_route_handlers::password_reset_complete),
+        )
 }
@@ -1,46 +1,181 @@
-use crate::{default_route_handlers::RegistrationDetails, get_app, get_app_state, migrations};
-use http::StatusCode;
-use reqwest::Client;
-use serde_json;
+use crate::{
+    app_route_handlers::promotion::{plan_movements, LeagueStandings, MovementDirection},
+    app_route_handlers::scheduling::{pack_into_rounds, round_robin},
+    app_route_handlers::scoring::{
+        resolve_result, sets_won, validate_score, MatchFormat, ResultOutcome, SetScore, Side,
+    },
+    app_route_handlers::standings::{
+        compute_league_table, LeagueTableRow, PointsScheme, TieBreaker,
+    },
+    app_route_handlers::MatchResult,
+    auth::{check_csrf, hash_token, ActiveSession, TokenScope},
+    config::{get_config, AppState},
+    default_route_handlers::{add_code, use_code, CodeType, ErrorResponse, RegistrationDetails},
+    email::retry_delay,
+    get_app,
+    middleware::{client_ip, RateLimitLayer},
+    migrations, totp,
+    utilities::{generate_unique_id, hash_password},
+};
+use axum::{routing::get, Router};
+use chrono::Utc;
+use http::{HeaderMap, Method, StatusCode};
+use reqwest::{Client, RequestBuilder};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
-async fn run_test_app() -> u16 {
-    let state = get_app_state().await;
-    migrations(state.clone())
+// The app served on a port of its own with a fresh in-memory database, so
+// tests running at the same time never see each other's data
+struct TestApp {
+    port: u16,
+    state: Arc<AppState>,
+    client: Client,
+}
+
+impl TestApp {
+    async fn new() -> Self {
+        let mut config = get_config();
+        config.database.file = ":memory:".to_string();
+        let state = Arc::new(AppState {
+            db_connection_pool: config.get_db_pool().await,
+            email_connection_pool: config.get_email_pool(),
+            config,
+        });
+        migrations(state.clone())
+            .await
+            .expect("Unable to complete migrations");
+        let app = get_app(state.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+        TestApp {
+            port,
+            state,
+            client: Client::new(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}:{}{}", SERVER_URL, self.port, path)
+    }
+
+    // A request sent as the session's user
+    fn request(&self, session: &TestSession, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, self.url(path))
+            .header("Cookie", format!("session-key={}", session.session_key))
+            .header("X-CSRF-Token", &session.csrf_token)
+    }
+
+    // A user logged in with a session made directly in the database
+    async fn session(&self, auth_level: i64) -> TestSession {
+        let username = format!("test{}", generate_unique_id(12));
+        let now = Utc::now().timestamp();
+        let session = TestSession {
+            session_key: generate_unique_id(40),
+            csrf_token: generate_unique_id(20),
+            username,
+        };
+        sqlx::query(
+            "INSERT INTO users(username,email,hashed_password,auth_level) values(?,?,'',?)",
+        )
+        .bind(&session.username)
+        .bind(format!("{}@test.com", session.username))
+        .bind(auth_level)
+        .execute(&self.state.db_connection_pool)
         .await
-        .expect("Unable to complete migrations");
-    let app = get_app(state.clone());
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO sessions(session_key,username,expiry,created_ts,last_seen_ts,csrf_token)
+            values(?,?,?,?,?,?)",
+        )
+        .bind(hash_token(&session.session_key))
+        .bind(&session.username)
+        .bind(now + 3600)
+        .bind(now)
+        .bind(now)
+        .bind(&session.csrf_token)
+        .execute(&self.state.db_connection_pool)
+        .await
+        .unwrap();
+        session
+    }
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    // A new bottom tier league with a player for each entry, linked to the
+    // account named if there is one
+    async fn league(&self, usernames: &[Option<&str>]) -> (i64, Vec<i64>) {
+        let league_id = sqlx::query(
+            "INSERT INTO leagues(league_name,league_tier)
+            SELECT 'Test League', MAX(league_tier)+1 FROM leagues",
+        )
+        .execute(&self.state.db_connection_pool)
+        .await
+        .unwrap()
+        .last_insert_rowid();
+        let mut player_ids = vec![];
+        for (number, username) in usernames.iter().enumerate() {
+            let player_id =
+                sqlx::query("INSERT INTO players(name,league_id,username) values(?,?,?)")
+                    .bind(format!("Player {}", number + 1))
+                    .bind(league_id)
+                    .bind(username)
+                    .execute(&self.state.db_connection_pool)
+                    .await
+                    .unwrap()
+                    .last_insert_rowid();
+            player_ids.push(player_id);
+        }
+        (league_id, player_ids)
+    }
 
-    let port = listener.local_addr().unwrap().port();
+    // An unplayed fixture in the active season
+    async fn fixture(&self, league_id: i64, player_one_id: i64, player_two_id: i64) -> i64 {
+        sqlx::query(
+            "INSERT INTO fixtures(season,league_id,player_one_id,player_two_id)
+            SELECT rowid,?,?,? FROM seasons WHERE status='active'",
+        )
+        .bind(league_id)
+        .bind(player_one_id)
+        .bind(player_two_id)
+        .execute(&self.state.db_connection_pool)
+        .await
+        .unwrap()
+        .last_insert_rowid()
+    }
+}
 
-    tokio::spawn(async move {
-        axum::serve(listener, app).await.unwrap();
-    });
-    port
+struct TestSession {
+    username: String,
+    session_key: String,
+    csrf_token: String,
 }
 
-async fn cleanup() -> Result<(), anyhow::Error> {
-    let state = get_app_state().await;
-    sqlx::query("DELETE FROM USERS")
-        .execute(&state.db_connection_pool)
-        .await?;
-    sqlx::query("DELETE FROM CODES")
-        .execute(&state.db_connection_pool)
-        .await?;
-    sqlx::query("DELETE FROM SESSIONS")
-        .execute(&state.db_connection_pool)
-        .await?;
-    Ok(())
+async fn json(response: reqwest::Response) -> Value {
+    response.json().await.unwrap()
 }
 
 const SERVER_URL: &str = "http://localhost";
 
 #[tokio::test]
 async fn register() {
-    let port = run_test_app().await;
+    let app = TestApp::new().await;
     let client = Client::new();
-    let url = format!("{}:{}/account/register", SERVER_URL, port);
+    let url = app.url("/account/register");
     let registration_request = RegistrationDetails {
         username: "JohnDoe".to_string(),
         email: "john@doe.gmail.com".to_string(),
@@ -57,14 +192,13 @@ async fn register() {
         .await
         .unwrap();
     assert_eq!(response.status(), StatusCode::OK);
-    let _ = cleanup().await;
 }
 
 #[tokio::test]
 async fn register_username_too_short() {
-    let port = run_test_app().await;
+    let app = TestApp::new().await;
     let client = Client::new();
-    let url = format!("{}:{}/account/register", SERVER_URL, port);
+    let url = app.url("/account/register");
     let registration_request = RegistrationDetails {
         username: "Jo".to_string(),
         email: "john@doe.gmail.com".to_string(),
@@ -80,15 +214,14 @@ async fn register_username_too_short() {
         .send()
         .await
         .unwrap();
-    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
-    let _ = cleanup().await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
 }
 
 #[tokio::test]
 async fn register_username_too_long() {
-    let port = run_test_app().await;
+    let app = TestApp::new().await;
     let client = Client::new();
-    let url = format!("{}:{}/account/register", SERVER_URL, port);
+    let url = app.url("/account/register");
     let registration_request = RegistrationDetails {
         username: "aabcdefghijklmnopqrstuvwxyabcdefghijklmnopqrstuvwxyabcdefghijklmnopqrstuvwxybcdefghijklmnopqrstuvwxya".to_string(),
         email: "john@doe.gmail.com".to_string(),
@@ -104,15 +237,14 @@ async fn register_username_too_long() {
         .send()
         .await
         .unwrap();
-    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
-    let _ = cleanup().await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
 }
 
 #[tokio::test]
 async fn register_invalid_email() {
-    let port = run_test_app().await;
+    let app = TestApp::new().await;
     let client = Client::new();
-    let url = format!("{}:{}/account/register", SERVER_URL, port);
+    let url = app.url("/account/register");
     let registration_request = RegistrationDetails {
         username: "JohnDoe".to_string(),
         email: "johndoe.gmail.com".to_string(),
@@ -128,15 +260,17 @@ async fn register_invalid_email() {
         .send()
         .await
         .unwrap();
-    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
-    let _ = cleanup().await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let error: ErrorResponse = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(error.code, "invalid_email");
+    assert_eq!(error.fields[0].field, "email");
 }
 
 #[tokio::test]
 async fn register_non_matching_passwords() {
-    let port = run_test_app().await;
+    let app = TestApp::new().await;
     let client = Client::new();
-    let url = format!("{}:{}/account/register", SERVER_URL, port);
+    let url = app.url("/account/register");
     let registration_request = RegistrationDetails {
         username: "JohnDoe".to_string(),
         email: "john@doe.gmail.com".to_string(),
@@ -152,15 +286,14 @@ async fn register_non_matching_passwords() {
         .send()
         .await
         .unwrap();
-    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
-    let _ = cleanup().await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
 }
 
 #[tokio::test]
 async fn register_password_too_short() {
-    let port = run_test_app().await;
+    let app = TestApp::new().await;
     let client = Client::new();
-    let url = format!("{}:{}/account/register", SERVER_URL, port);
+    let url = app.url("/account/register");
     let registration_request = RegistrationDetails {
         username: "JohnDoe".to_string(),
         email: "john@doe.gmail.com".to_string(),
@@ -176,15 +309,14 @@ async fn register_password_too_short() {
         .send()
         .await
         .unwrap();
-    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
-    let _ = cleanup().await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
 }
 
 #[tokio::test]
 async fn register_password_too_long() {
-    let port = run_test_app().await;
+    let app = TestApp::new().await;
     let client = Client::new();
-    let url = format!("{}:{}/account/register", SERVER_URL, port);
+    let url = app.url("/account/register");
     let registration_request = RegistrationDetails {
         username: "JohnDoe".to_string(),
         email: "john@doe.gmail.com".to_string(),
@@ -200,6 +332,943 @@ async fn register_password_too_long() {
         .send()
         .await
         .unwrap();
-    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
-    let _ = cleanup().await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[test]
+fn round_robin_pairs_everyone_once() {
+    let players = vec![1, 2, 3, 4, 5, 6, 7];
+    let rounds = round_robin(&players);
+    assert_eq!(rounds.len(), 7);
+
+    let mut pairs = HashSet::new();
+    for round in rounds.iter() {
+        // With seven players one sits out each round
+        assert_eq!(round.len(), 3);
+        let mut playing = HashSet::new();
+        for (player_one, player_two) in round {
+            assert!(playing.insert(*player_one));
+            assert!(playing.insert(*player_two));
+            assert!(pairs.insert((*player_one.min(player_two), *player_one.max(player_two))));
+        }
+    }
+    assert_eq!(pairs.len(), 21);
+}
+
+#[test]
+fn late_joiner_fills_existing_byes() {
+    let existing_rounds = round_robin(&[1, 2, 3]);
+    let new_pairs = vec![(4, 1), (4, 2), (4, 3)];
+    let rounds = pack_into_rounds(&new_pairs, &existing_rounds);
+    // Each existing round had a bye so no extra rounds are needed
+    assert_eq!(rounds.len(), existing_rounds.len());
+    assert!(rounds.iter().all(|round| round.len() == 1));
+}
+
+fn sets(scores: &[(i64, i64)]) -> Vec<SetScore> {
+    scores
+        .iter()
+        .map(|&(player_one, player_two)| SetScore {
+            player_one,
+            player_two,
+        })
+        .collect()
+}
+
+#[test]
+fn valid_scores_are_accepted() {
+    let rules = MatchFormat::TwoSetsMatchTiebreak.rules();
+    assert!(validate_score(&rules, &sets(&[(6, 4), (7, 6)]), true).is_ok());
+    assert!(validate_score(&rules, &sets(&[(6, 4), (5, 7), (12, 10)]), true).is_ok());
+
+    let rules = MatchFormat::Fast4.rules();
+    assert!(validate_score(&rules, &sets(&[(4, 3), (2, 4), (4, 1)]), true).is_ok());
+
+    let rules = MatchFormat::ProSet.rules();
+    assert!(validate_score(&rules, &sets(&[(9, 8)]), true).is_ok());
+}
+
+#[test]
+fn invalid_scores_report_the_set() {
+    let rules = MatchFormat::TwoSetsMatchTiebreak.rules();
+
+    let error = validate_score(&rules, &sets(&[(6, 4), (3, 2)]), true).unwrap_err();
+    assert_eq!(error.errors.len(), 1);
+    assert_eq!(error.errors[0].set, Some(2));
+
+    let error = validate_score(&rules, &sets(&[(6, 4), (4, 6), (5, 5)]), true).unwrap_err();
+    assert_eq!(error.errors[0].set, Some(3));
+
+    // No deciding tiebreak once the match is already won
+    let error = validate_score(&rules, &sets(&[(6, 4), (6, 4), (10, 8)]), true).unwrap_err();
+    assert_eq!(error.errors[0].set, Some(3));
+
+    // A completed match must have a winner
+    let error = validate_score(&rules, &sets(&[(6, 4), (4, 6)]), true).unwrap_err();
+    assert_eq!(error.errors[0].set, None);
+}
+
+#[test]
+fn outcomes_need_a_consistent_score() {
+    let rules = MatchFormat::TwoSetsMatchTiebreak.rules();
+
+    // A retirement keeps the partial score and takes the declared winner
+    let winner = resolve_result(
+        &rules,
+        ResultOutcome::Retired,
+        &sets(&[(6, 4), (2, 3)]),
+        Some(Side::PlayerTwo),
+        true,
+    );
+    assert_eq!(winner.unwrap(), Some(Side::PlayerTwo));
+    // But not once the score has already decided the match
+    assert!(resolve_result(
+        &rules,
+        ResultOutcome::Retired,
+        &sets(&[(6, 0), (6, 0)]),
+        Some(Side::PlayerTwo),
+        true
+    )
+    .is_err());
+
+    assert!(resolve_result(&rules, ResultOutcome::Walkover, &[], None, true).is_err());
+    assert!(resolve_result(
+        &rules,
+        ResultOutcome::Walkover,
+        &sets(&[(6, 0)]),
+        Some(Side::PlayerOne),
+        true
+    )
+    .is_err());
+    assert_eq!(
+        resolve_result(&rules, ResultOutcome::DoubleWalkover, &[], None, true).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn outbox_retries_back_off_exponentially() {
+    assert_eq!(retry_delay(1, 30), 30);
+    assert_eq!(retry_delay(2, 30), 60);
+    assert_eq!(retry_delay(4, 30), 240);
+}
+
+#[test]
+fn state_changing_requests_need_the_csrf_token() {
+    let session = ActiveSession {
+        username: "JohnDoe".to_string(),
+        remember_me: false,
+        csrf_token: Some("token".to_string()),
+    };
+    let mut headers = HeaderMap::new();
+    assert!(check_csrf(&Method::GET, &headers, &session).is_ok());
+    assert!(check_csrf(&Method::PUT, &headers, &session).is_err());
+
+    headers.insert("X-CSRF-Token", "wrong".parse().unwrap());
+    assert!(check_csrf(&Method::POST, &headers, &session).is_err());
+    headers.insert("X-CSRF-Token", "token".parse().unwrap());
+    assert!(check_csrf(&Method::POST, &headers, &session).is_ok());
+}
+
+#[test]
+fn token_scopes_limit_what_a_token_can_do() {
+    assert!(TokenScope::ReadOnly.allows(&Method::GET, "/api/me"));
+    assert!(!TokenScope::ReadOnly.allows(&Method::PUT, "/api/result"));
+    // Read only access comes from the allow-list, not just the method
+    assert!(TokenScope::ReadOnly.allows(&Method::GET, "/api/player/3"));
+    assert!(!TokenScope::ReadOnly.allows(&Method::GET, "/api/allFixtures"));
+
+    assert!(TokenScope::ResultsWrite.allows(&Method::PUT, "/api/result"));
+    assert!(TokenScope::ResultsWrite.allows(&Method::PATCH, "/api/result/3/confirm"));
+    assert!(!TokenScope::ResultsWrite.allows(&Method::POST, "/api/league"));
+
+    assert!(TokenScope::Admin.allows(&Method::POST, "/api/league"));
+    // Tokens can't be used to manage the account, including minting more tokens
+    assert!(!TokenScope::Admin.allows(&Method::POST, "/account/apiTokens"));
+}
+
+#[test]
+fn totp_codes_match_the_rfc_test_vectors() {
+    // The RFC 6238 SHA-1 secret "12345678901234567890" in base32
+    let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    assert_eq!(
+        totp::code_at(secret, totp::time_step(59)).unwrap(),
+        "287082"
+    );
+    assert_eq!(
+        totp::code_at(secret, totp::time_step(1111111109)).unwrap(),
+        "081804"
+    );
+
+    let step = totp::verify(secret, "081804", 1111111109, 0).unwrap();
+    // A code can't be used again once its step has been
+    assert!(totp::verify(secret, "081804", 1111111109, step).is_none());
+    assert!(totp::verify(secret, "000000", 1111111109, 0).is_none());
+}
+
+#[tokio::test]
+async fn password_reset_hides_unknown_emails() {
+    let app = TestApp::new().await;
+    let client = Client::new();
+    let url = app.url("/account/resetPassword");
+
+    let response = client
+        .post(url)
+        .body("\"nobody@doe.gmail.com\"")
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.text().await.unwrap(),
+        "If that email is registered a password reset email has been sent"
+    );
+}
+
+#[tokio::test]
+async fn rate_limit_counts_each_forwarded_client_separately() {
+    let proxy: IpAddr = "127.0.0.1".parse().unwrap();
+    let app = Router::new()
+        .route("/", get(|| async { "ok" }))
+        .route_layer(RateLimitLayer::new(1, Duration::from_secs(60), vec![proxy]));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+
+    let client = Client::new();
+    let url = format!("{}:{}/", SERVER_URL, port);
+    let request = |forwarded_for: &str| {
+        client
+            .get(&url)
+            .header("X-Forwarded-For", forwarded_for)
+            .send()
+    };
+    assert_eq!(
+        request("203.0.113.1").await.unwrap().status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        request("203.0.113.1").await.unwrap().status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(
+        request("203.0.113.2").await.unwrap().status(),
+        StatusCode::OK
+    );
+}
+
+#[test]
+fn forwarded_addresses_are_only_trusted_from_proxies() {
+    let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+    let client: IpAddr = "203.0.113.7".parse().unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "X-Forwarded-For",
+        "198.51.100.1, 203.0.113.7".parse().unwrap(),
+    );
+
+    // A client can't choose its own address by sending the header directly
+    assert_eq!(client_ip(&headers, client, &[proxy]), client);
+    // Earlier entries could have been sent by the client so the last is used
+    assert_eq!(client_ip(&headers, proxy, &[proxy]), client);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "Forwarded",
+        "for=\"[2001:db8::1]:4711\";proto=https".parse().unwrap(),
+    );
+    assert_eq!(
+        client_ip(&headers, proxy, &[proxy]),
+        "2001:db8::1".parse::<IpAddr>().unwrap()
+    );
+}
+
+// A league whose table has the given players in finishing order
+fn standings(league_id: i64, places: i64, player_ids: &[i64]) -> LeagueStandings {
+    LeagueStandings {
+        league_id,
+        promotion_places: places,
+        relegation_places: places,
+        table: player_ids
+            .iter()
+            .map(|&player_id| LeagueTableRow::new(player_id, format!("Player {}", player_id)))
+            .collect(),
+    }
+}
+
+#[test]
+fn top_and_bottom_players_move_between_tiers() {
+    let leagues = vec![
+        standings(1, 2, &[1, 2, 3, 4, 5]),
+        standings(2, 2, &[6, 7, 8, 9, 10]),
+        standings(3, 2, &[11, 12, 13, 14, 15]),
+    ];
+    let movements = plan_movements(&leagues);
+    let moves: Vec<(i64, i64, MovementDirection)> = movements
+        .iter()
+        .map(|movement| {
+            (
+                movement.player_id,
+                movement.to_league_id,
+                movement.direction,
+            )
+        })
+        .collect();
+    // Nobody goes up from the top tier or down from the bottom one
+    assert_eq!(
+        moves,
+        vec![
+            (4, 2, MovementDirection::Relegation),
+            (5, 2, MovementDirection::Relegation),
+            (6, 1, MovementDirection::Promotion),
+            (7, 1, MovementDirection::Promotion),
+            (9, 3, MovementDirection::Relegation),
+            (10, 3, MovementDirection::Relegation),
+            (11, 2, MovementDirection::Promotion),
+            (12, 2, MovementDirection::Promotion),
+        ]
+    );
+    assert_eq!(movements[1].final_position, 5);
+}
+
+#[test]
+fn movement_places_are_capped_at_the_league_size() {
+    let leagues = vec![standings(1, 0, &[1, 2]), standings(2, 5, &[3, 4, 5])];
+    let movements = plan_movements(&leagues);
+    // Everyone is promoted and nobody is also relegated
+    assert_eq!(movements.len(), 3);
+    assert!(movements
+        .iter()
+        .all(|movement| movement.direction == MovementDirection::Promotion));
+
+    let leagues = vec![standings(1, 4, &[1, 2, 3]), standings(2, 0, &[4])];
+    let movements = plan_movements(&leagues);
+    assert_eq!(movements.len(), 3);
+    assert!(movements.iter().all(|movement| movement.to_league_id == 2));
+
+    // A single league has nowhere to move anyone
+    assert!(plan_movements(&[standings(1, 3, &[1, 2, 3])]).is_empty());
+}
+
+// A confirmed result between two players, the winner decided by the score
+fn confirmed_result(player_one_id: i64, player_two_id: i64, scores: &[(i64, i64)]) -> MatchResult {
+    let (won, lost) = sets_won(&sets(scores));
+    serde_json::from_value(serde_json::json!({
+        "fixture_id": 0,
+        "season": 1,
+        "league_id": 1,
+        "player_one_id": player_one_id,
+        "player_two_id": player_two_id,
+        "sets": sets(scores),
+        "outcome": "played",
+        "completed": 1,
+        "winner": if won > lost { player_one_id } else { player_two_id },
+        "result_status": "confirmed",
+    }))
+    .unwrap()
+}
+
+fn league_table_order(
+    results: &[MatchResult],
+    points_scheme: &PointsScheme,
+) -> Vec<(i64, i64, Option<TieBreaker>)> {
+    let players = vec![1, 2, 3, 4];
+    let player_map: HashMap<i64, String> = players
+        .iter()
+        .map(|&player_id| (player_id, format!("Player {}", player_id)))
+        .collect();
+    compute_league_table(
+        players,
+        player_map,
+        results,
+        MatchFormat::TwoSetsMatchTiebreak,
+        points_scheme,
+    )
+    .into_iter()
+    .filter(|row| row.played > 0)
+    .map(|row| (row.player_id, row.points, row.tie_breaker))
+    .collect()
+}
+
+fn points_scheme(points_per_loss: i64, points_per_set: i64) -> PointsScheme {
+    PointsScheme {
+        points_per_win: 2,
+        points_per_loss,
+        points_per_set,
+        points_per_walkover: 2,
+        points_per_retirement: 0,
+        points_per_walkover_loss: 0,
+        points_per_double_walkover: 0,
+        tie_breakers: vec![
+            TieBreaker::HeadToHead,
+            TieBreaker::SetDifference,
+            TieBreaker::GameDifference,
+        ],
+    }
+}
+
+#[test]
+fn head_to_head_separates_players_level_on_points() {
+    let results = vec![
+        confirmed_result(1, 2, &[(6, 4), (6, 4)]),
+        confirmed_result(2, 4, &[(6, 0), (6, 0)]),
+        confirmed_result(3, 1, &[(6, 0), (6, 0)]),
+        confirmed_result(3, 4, &[(6, 0), (6, 0)]),
+    ];
+    // Points come from the scheme: 2 a win, 1 a loss and 1 a set
+    assert_eq!(
+        league_table_order(&results, &points_scheme(1, 1)),
+        vec![
+            (3, 8, None),
+            // Player 2 has the better game difference but lost to player 1
+            (1, 5, Some(TieBreaker::HeadToHead)),
+            (2, 5, Some(TieBreaker::HeadToHead)),
+            (4, 2, None),
+        ]
+    );
+}
+
+#[test]
+fn set_difference_orders_a_head_to_head_cycle() {
+    // Each player beats one of the others so head-to-head can't separate them
+    let results = vec![
+        confirmed_result(1, 2, &[(6, 0), (6, 0)]),
+        confirmed_result(2, 3, &[(6, 4), (4, 6), (10, 8)]),
+        confirmed_result(3, 1, &[(6, 4), (4, 6), (10, 5)]),
+    ];
+    assert_eq!(
+        league_table_order(&results, &points_scheme(0, 0)),
+        vec![
+            (1, 2, Some(TieBreaker::SetDifference)),
+            (3, 2, Some(TieBreaker::SetDifference)),
+            (2, 2, Some(TieBreaker::SetDifference)),
+        ]
+    );
+}
+
+const PLAYER: i64 = 50;
+const CAPTAIN: i64 = 60;
+const ORGANISER: i64 = 80;
+
+#[tokio::test]
+async fn players_can_be_searched_and_filtered() {
+    let app = TestApp::new().await;
+    let organiser = app.session(ORGANISER).await;
+    let (league_id, _) = app.league(&[]).await;
+
+    let mut player_ids = vec![];
+    for name in ["Ann 50% Smith", "Ann 500 Smith"] {
+        let response = app
+            .request(&organiser, Method::POST, "/api/player")
+            .json(&serde_json::json!({
+                "name": name,
+                "league_id": league_id,
+                "preferred_hand": "left",
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        player_ids.push(json(response).await["player_id"].as_i64().unwrap());
+    }
+
+    // The % is matched literally rather than as a wildcard
+    let response = app
+        .request(
+            &organiser,
+            Method::GET,
+            &format!("/api/players?search=50%25&league_id={}", league_id),
+        )
+        .send()
+        .await
+        .unwrap();
+    let players = json(response).await;
+    assert_eq!(players.as_array().unwrap().len(), 1);
+    assert_eq!(players[0]["player_id"], player_ids[0]);
+
+    let response = app
+        .request(
+            &organiser,
+            Method::PATCH,
+            &format!("/api/player/{}", player_ids[1]),
+        )
+        .json(&serde_json::json!({ "active": false, "preferred_hand": null }))
+        .send()
+        .await
+        .unwrap();
+    let player = json(response).await;
+    assert_eq!(player["active"], false);
+    assert_eq!(player["preferred_hand"], Value::Null);
+
+    let response = app
+        .request(
+            &organiser,
+            Method::GET,
+            &format!("/api/players?league_id={}&active=false", league_id),
+        )
+        .send()
+        .await
+        .unwrap();
+    let players = json(response).await;
+    assert_eq!(players.as_array().unwrap().len(), 1);
+    assert_eq!(players[0]["player_id"], player_ids[1]);
+
+    // Fields left out of an update are kept
+    let response = app
+        .request(
+            &organiser,
+            Method::PATCH,
+            &format!("/api/player/{}", player_ids[0]),
+        )
+        .json(&serde_json::json!({ "availability": "Weekends" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(json(response).await["preferred_hand"], "left");
+}
+
+#[tokio::test]
+async fn players_with_fixtures_cannot_be_deleted() {
+    let app = TestApp::new().await;
+    let organiser = app.session(ORGANISER).await;
+    let (league_id, opponent_ids) = app.league(&[None]).await;
+
+    let response = app
+        .request(&organiser, Method::POST, "/api/player")
+        .json(&serde_json::json!({ "name": "Bo Jones", "league_id": league_id }))
+        .send()
+        .await
+        .unwrap();
+    let player_id = json(response).await["player_id"].as_i64().unwrap();
+    let fixture_id = app.fixture(league_id, player_id, opponent_ids[0]).await;
+
+    let player_path = format!("/api/player/{}", player_id);
+    let response = app
+        .request(&organiser, Method::DELETE, &player_path)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(json(response).await["code"], "player_has_fixtures");
+
+    sqlx::query("DELETE FROM fixtures WHERE rowid=?")
+        .bind(fixture_id)
+        .execute(&app.state.db_connection_pool)
+        .await
+        .unwrap();
+    let response = app
+        .request(&organiser, Method::DELETE, &player_path)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = app
+        .request(&organiser, Method::GET, &player_path)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn results_count_once_the_opponent_or_an_admin_confirms_them() {
+    let app = TestApp::new().await;
+    let player_one = app.session(PLAYER).await;
+    let player_two = app.session(PLAYER).await;
+    let organiser = app.session(ORGANISER).await;
+    let (league_id, player_ids) = app
+        .league(&[Some(&player_one.username), Some(&player_two.username)])
+        .await;
+    let (player_one_id, player_two_id) = (player_ids[0], player_ids[1]);
+    let first_fixture = app.fixture(league_id, player_one_id, player_two_id).await;
+    let second_fixture = app.fixture(league_id, player_two_id, player_one_id).await;
+
+    let result = |player_one_id: i64, player_two_id: i64| {
+        serde_json::json!({
+            "league_id": league_id,
+            "player_one_id": player_one_id,
+            "player_two_id": player_two_id,
+            "sets": [{"player_one": 6, "player_two": 4}, {"player_one": 6, "player_two": 4}],
+            "completed": 1,
+        })
+    };
+    let submit = |session: &TestSession, body: Value| {
+        app.request(session, Method::PUT, "/api/myResult")
+            .json(&body)
+            .send()
+    };
+    let respond = |session: &TestSession, fixture_id: i64, action: &str| {
+        app.request(
+            session,
+            Method::PATCH,
+            &format!("/api/result/{}/{}", fixture_id, action),
+        )
+        .json(&serde_json::json!({ "reason": "That's not the score" }))
+        .send()
+    };
+    let played = || async {
+        let response = app
+            .client
+            .get(app.url(&format!("/api/leagueTable/{}", league_id)))
+            .send()
+            .await
+            .unwrap();
+        let table = json(response).await;
+        table["league_table"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row["played"].as_i64().unwrap())
+            .sum::<i64>()
+    };
+
+    // A match still in progress can't be left for the opponent to confirm
+    let mut unfinished = result(player_one_id, player_two_id);
+    unfinished["sets"] = serde_json::json!([{"player_one": 6, "player_two": 4}]);
+    unfinished["completed"] = 0.into();
+    let response = submit(&player_one, unfinished).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(json(response).await["code"], "result_not_completed");
+
+    let response = submit(&player_one, result(player_one_id, player_two_id)).await;
+    assert_eq!(response.unwrap().status(), StatusCode::RESET_CONTENT);
+    // A pending result can't be replaced or confirmed by whoever submitted it
+    let response = submit(&player_one, result(player_one_id, player_two_id)).await;
+    assert_eq!(
+        json(response.unwrap()).await["code"],
+        "result_already_submitted"
+    );
+    let response = respond(&player_one, first_fixture, "confirm").await;
+    assert_eq!(response.unwrap().status(), StatusCode::FORBIDDEN);
+    assert_eq!(played().await, 0);
+
+    let response = respond(&player_two, first_fixture, "dispute").await;
+    assert_eq!(response.unwrap().status(), StatusCode::RESET_CONTENT);
+    // The dispute stands until an admin deals with it
+    let response = respond(&player_two, first_fixture, "confirm").await;
+    assert_eq!(json(response.unwrap()).await["code"], "result_not_pending");
+    let response = submit(&player_one, result(player_one_id, player_two_id)).await;
+    assert_eq!(response.unwrap().status(), StatusCode::CONFLICT);
+    assert_eq!(played().await, 0);
+
+    let response = app
+        .request(
+            &organiser,
+            Method::PATCH,
+            &format!("/api/admin/result/{}", first_fixture),
+        )
+        .json(&serde_json::json!({ "resolution": "confirm" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::RESET_CONTENT);
+    assert_eq!(played().await, 2);
+
+    let response = submit(&player_two, result(player_two_id, player_one_id)).await;
+    assert_eq!(response.unwrap().status(), StatusCode::RESET_CONTENT);
+    let response = respond(&player_one, second_fixture, "confirm").await;
+    assert_eq!(response.unwrap().status(), StatusCode::RESET_CONTENT);
+    assert_eq!(played().await, 4);
+}
+
+#[tokio::test]
+async fn invites_link_one_unclaimed_player_to_the_caller() {
+    let app = TestApp::new().await;
+    let organiser = app.session(ORGANISER).await;
+    let claimant = app.session(PLAYER).await;
+    let latecomer = app.session(PLAYER).await;
+    let unlinked = app.session(PLAYER).await;
+    let (_, player_ids) = app.league(&[None, Some(&latecomer.username)]).await;
+
+    let invite = |player_id: i64| {
+        let request = app.request(
+            &organiser,
+            Method::POST,
+            &format!("/api/player/{}/invite", player_id),
+        );
+        async move {
+            let response = request.send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+            json(response).await["code"].as_str().unwrap().to_string()
+        }
+    };
+    let claim = |session: &TestSession, code: String| {
+        app.request(session, Method::POST, "/api/me/claimInvite")
+            .json(&serde_json::json!({ "code": code }))
+            .send()
+    };
+
+    let code = invite(player_ids[0]).await;
+    let stored: String = sqlx::query_scalar("SELECT code FROM player_invites")
+        .fetch_one(&app.state.db_connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(stored, hash_token(&code));
+    let response = claim(&claimant, code.clone()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    // The same code can't be used again, even by an unlinked account
+    let response = claim(&unlinked, code).await.unwrap();
+    assert_eq!(json(response).await["code"], "invalid_invite_code");
+
+    // Nor can a fresh invite take over a player someone has already claimed
+    for player_id in player_ids {
+        let code = invite(player_id).await;
+        let response = claim(&unlinked, code).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(json(response).await["code"], "player_already_linked");
+    }
+}
+
+#[tokio::test]
+async fn me_only_lists_the_callers_own_fixtures() {
+    let app = TestApp::new().await;
+    let session = app.session(PLAYER).await;
+    let (league_id, player_ids) = app.league(&[Some(&session.username), None, None]).await;
+    let (player_id, opponent_id, other_id) = (player_ids[0], player_ids[1], player_ids[2]);
+    let own_fixtures = [
+        app.fixture(league_id, player_id, opponent_id).await,
+        app.fixture(league_id, other_id, player_id).await,
+    ];
+    app.fixture(league_id, opponent_id, other_id).await;
+
+    let response = app
+        .request(&session, Method::GET, "/api/me")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let me = json(response).await;
+    assert_eq!(me["player"]["player_id"], player_id);
+    let mut fixture_ids: Vec<i64> = me["upcoming_fixtures"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|fixture| fixture["fixture_id"].as_i64().unwrap())
+        .collect();
+    fixture_ids.sort();
+    assert_eq!(fixture_ids, own_fixtures);
+    assert!(me["completed_fixtures"].as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn routes_refuse_sessions_below_their_role() {
+    let app = TestApp::new().await;
+    let player = app.session(PLAYER).await;
+    let organiser = app.session(ORGANISER).await;
+
+    let response = app
+        .client
+        .get(app.url("/api/players"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app
+        .request(&player, Method::GET, "/api/players")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let error = json(response).await;
+    assert_eq!(error["code"], "insufficient_role");
+    assert_eq!(
+        error["message"],
+        "This needs the league organiser role or higher"
+    );
+    let response = app
+        .request(&player, Method::GET, "/api/me")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .request(&organiser, Method::GET, "/api/players")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn two_factor_policy_covers_every_role_above_player() {
+    let app = TestApp::new().await;
+    let player = app.session(PLAYER).await;
+    let captain = app.session(CAPTAIN).await;
+    let organiser = app.session(ORGANISER).await;
+    let (league_id, player_ids) = app.league(&[None, None]).await;
+    sqlx::query("UPDATE settings SET value='true' WHERE name='require_admin_two_factor'")
+        .execute(&app.state.db_connection_pool)
+        .await
+        .unwrap();
+
+    let result = serde_json::json!({
+        "league_id": league_id,
+        "player_one_id": player_ids[0],
+        "player_two_id": player_ids[1],
+        "sets": [{"player_one": 6, "player_two": 4}, {"player_one": 6, "player_two": 4}],
+        "completed": 1,
+    });
+    for session in [&captain, &organiser] {
+        let response = app
+            .request(session, Method::PUT, "/api/result")
+            .json(&result)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+    let table_path = format!("/api/admin/leagueTable/{}", league_id);
+    let response = app
+        .request(&captain, Method::GET, &table_path)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(json(response).await["code"], "two_factor_required");
+
+    // Players aren't asked for a second factor
+    let response = app
+        .request(&player, Method::GET, "/api/me")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn wrong_passwords_sent_at_once_still_lock_the_account() {
+    let app = TestApp::new().await;
+    let session = app.session(PLAYER).await;
+    let max_login_attempts = app.state.config.security.max_login_attempts;
+    sqlx::query("UPDATE users SET hashed_password=? WHERE username=?")
+        .bind(hash_password("TestPassword"))
+        .bind(&session.username)
+        .execute(&app.state.db_connection_pool)
+        .await
+        .unwrap();
+
+    let guesses = (0..max_login_attempts).map(|guess| {
+        app.client
+            .post(app.url("/account/login"))
+            .json(&serde_json::json!({
+                "email": format!("{}@test.com", session.username),
+                "password": format!("guess {}", guess),
+            }))
+            .send()
+    });
+    for response in futures::future::join_all(guesses).await {
+        assert_eq!(json(response.unwrap()).await["code"], "incorrect_password");
+    }
+
+    let locked_until: i64 = sqlx::query_scalar("SELECT locked_until FROM users WHERE username=?")
+        .bind(&session.username)
+        .fetch_one(&app.state.db_connection_pool)
+        .await
+        .unwrap();
+    assert!(locked_until > Utc::now().timestamp());
+}
+
+#[tokio::test]
+async fn registration_queues_its_verification_email_or_creates_nothing() {
+    let app = TestApp::new().await;
+    let register = |username: &str, email: &str| {
+        app.client
+            .post(app.url("/account/register"))
+            .json(&RegistrationDetails {
+                username: username.to_string(),
+                email: email.to_string(),
+                password: "TestPassword".to_string(),
+                confirm_password: "TestPassword".to_string(),
+            })
+            .send()
+    };
+
+    let response = register("John Doe", "john doe@doe.gmail.com")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(json(response).await["code"], "invalid_email");
+
+    // Usernames aren't part of the recipient so any characters can be used
+    let response = register("Doe, John", "john@doe.gmail.com").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let recipient: String =
+        sqlx::query_scalar("SELECT recipient FROM outbox WHERE template='verification'")
+            .fetch_one(&app.state.db_connection_pool)
+            .await
+            .unwrap();
+    assert_eq!(recipient, "john@doe.gmail.com");
+    let codes: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM codes WHERE email=?")
+        .bind(&recipient)
+        .fetch_one(&app.state.db_connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(codes, 1);
+}
+
+#[tokio::test]
+async fn emailed_codes_can_be_typed_in_any_case() {
+    let app = TestApp::new().await;
+    let email = "john@doe.gmail.com";
+    let code = add_code(app.state.clone(), email, CodeType::EmailVerification)
+        .await
+        .unwrap();
+
+    let typed = format!(" {} ", code.to_lowercase());
+    assert!(use_code(
+        app.state.clone(),
+        email,
+        &typed,
+        CodeType::EmailVerification
+    )
+    .await
+    .unwrap());
+    // Still only once
+    assert!(
+        !use_code(app.state.clone(), email, &code, CodeType::EmailVerification)
+            .await
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn fixtures_are_only_generated_for_seasons_still_running() {
+    let app = TestApp::new().await;
+    let organiser = app.session(ORGANISER).await;
+    let season_id = sqlx::query(
+        "INSERT INTO seasons(season_name,start_date,end_date,status)
+        values('Last Season','2020-01-01','2020-06-30','active')",
+    )
+    .execute(&app.state.db_connection_pool)
+    .await
+    .unwrap()
+    .last_insert_rowid();
+
+    let response = app
+        .request(
+            &organiser,
+            Method::POST,
+            &format!("/api/allFixtures?season_id={}", season_id),
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(json(response).await["code"], "season_already_finished");
+    let fixtures: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM fixtures WHERE season=?")
+        .bind(season_id)
+        .fetch_one(&app.state.db_connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(fixtures, 0);
 }
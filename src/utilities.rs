@@ -3,27 +3,7 @@ use argon2::{
     Argon2,
 };
 use rand::{thread_rng, Rng};
-
-// #[derive(Debug)]
-// pub struct _Email<'a> {
-//     pub from: &'a str,
-//     pub reply_to: Option<&'a str>,
-//     pub to: &'a str,
-//     pub subject: String,
-//     pub body: String,
-// }
-// pub async fn send_email(state: Arc<AppState>, email: Email<'_>) -> Result<(), anyhow::Error> {
-//     println!("The email to be sent to the user is {:?}", email);
-//     // let email = Message::builder()
-//     //     .from(email.from.parse()?)
-//     //     .reply_to(email.reply_to.unwrap_or_default().parse()?)
-//     //     .to(email.to.parse()?)
-//     //     .subject(email.subject)
-//     //     .body(email.body)?;
-//     // Send the email via remote relay
-//     //let _ = state.email_connection_pool.send(&email);
-//     Ok(())
-// }
+use serde::{Deserialize, Deserializer};
 
 pub fn hash_password(password: &str) -> String {
     let salt = SaltString::generate(&mut OsRng);
@@ -59,3 +39,13 @@ pub fn generate_unique_id(length: u8) -> String {
     }
     id
 }
+
+// For optional fields that can also be set to null, so a missing field comes
+// out as None and a null one as Some(None). Use with #[serde(default)].
+pub fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}